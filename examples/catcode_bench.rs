@@ -0,0 +1,73 @@
+//! Tokenize a document with frequent catcode changes, like `listings` input.
+//!
+//! Run with `cargo run --release --example catcode_bench [lines]`. The number
+//! of tokens and a checksum of them are printed with the time taken, the
+//! checksum of a small workload is pinned by the test below.
+use std::time::Instant;
+use tex_parser::token::{Category, Token, Tokenizer, TokenizerInteraction};
+
+/// Generate `count` lines of mixed text, control sequences and comments.
+fn workload(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| match i % 4 {
+            0 => format!("\\begin{{lstlisting}}@x{}@ {{a_b}}", i),
+            1 => format!("  int x@{} = \"{}\"; % comment", i % 10, i),
+            2 => "\\end{lstlisting} some $math^2$ text\\\\".to_owned(),
+            _ => String::new(),
+        })
+        .collect()
+}
+
+/// Tokenize `lines`, changing two catcodes every 50 tokens.
+/// Returns the number of tokens and a FNV-1a hash of their debug output.
+fn run(lines: Vec<String>) -> (usize, u64) {
+    let mut tokenizer = Tokenizer::new(lines.into_iter());
+    let mut count = 0;
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    while let Some(token) = tokenizer.next() {
+        for byte in format!("{:?}", token).bytes() {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+        if count % 50 == 0 {
+            let letter = count % 100 == 0 || matches!(token, Token::Character(_, Category::Cat10));
+            let cat = if letter {
+                Category::Cat11
+            } else {
+                Category::Cat12
+            };
+            tokenizer.catcode('@', cat);
+            tokenizer.catcode('"', Category::Cat12);
+        }
+        count += 1;
+    }
+    (count, hash)
+}
+
+fn main() {
+    let count = std::env::args()
+        .nth(1)
+        .map(|n| n.parse().expect("the number of lines"))
+        .unwrap_or(200_000);
+    let lines = workload(count);
+    let start = Instant::now();
+    let (tokens, hash) = run(lines);
+    let elapsed = start.elapsed();
+    println!(
+        "{} lines, {} tokens in {:.3}s ({:.1} Mtokens/s), checksum {:016x}",
+        count,
+        tokens,
+        elapsed.as_secs_f64(),
+        tokens as f64 / elapsed.as_secs_f64() / 1e6,
+        hash
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn golden_checksum() {
+        assert_eq!(run(workload(1000)), (19944, 0x7c6b_ffdb_253f_ee43));
+    }
+}
@@ -0,0 +1,138 @@
+/// A common error type for the fallible operations of the crate.
+use crate::macros::ExpansionError;
+use crate::token::*;
+use std::error::Error;
+
+/// Any error which can occur while processing TeX input.
+///
+/// The fallible functions of the crate return this error, so callers combining several
+/// steps can use `?` throughout.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextileError {
+    /// An error while scanning quantities or building macro definitions
+    Expansion(ExpansionError),
+    /// Input without the expected structure at the given span, e.g. a group which is not closed
+    Syntax(Span),
+    /// A diagnostic of the tokenizer, e.g. an invalid character
+    Diagnostic(OtherToken, Span),
+}
+
+impl TextileError {
+    /// The error reported by `token`, if it is a diagnostic of the tokenizer.
+    ///
+    /// Invalid characters and runaway input are errors, while comments, ignored
+    /// characters and skipped input are not.
+    pub fn from_token(token: &Token) -> Option<TextileError> {
+        match token {
+            Token::Other(other @ OtherToken::InvalidCharacter(_), span)
+            | Token::Other(other @ OtherToken::Runaway(_), span) => {
+                Some(TextileError::Diagnostic(other.clone(), span.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<ExpansionError> for TextileError {
+    fn from(error: ExpansionError) -> Self {
+        TextileError::Expansion(error)
+    }
+}
+
+impl std::fmt::Display for TextileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextileError::Expansion(error) => write!(f, "{}", error),
+            TextileError::Syntax(span) => write!(
+                f,
+                "Unexpected Input at {}:{}-{}",
+                span.line, span.start, span.end
+            ),
+            TextileError::Diagnostic(OtherToken::InvalidCharacter(c), span) => write!(
+                f,
+                "Invalid Character {:?} at {}:{}-{}",
+                c, span.line, span.start, span.end
+            ),
+            TextileError::Diagnostic(OtherToken::Runaway(text), span) => write!(
+                f,
+                "Runaway Input {:?} at {}:{}-{}",
+                text, span.line, span.start, span.end
+            ),
+            TextileError::Diagnostic(other, span) => write!(
+                f,
+                "Unexpected Input {:?} at {}:{}-{}",
+                other.to_string(),
+                span.line,
+                span.start,
+                span.end
+            ),
+        }
+    }
+}
+
+impl Error for TextileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TextileError::Expansion(error) => Some(error),
+            TextileError::Syntax(_) => None,
+            TextileError::Diagnostic(_, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_test {
+    use crate::error::*;
+    use crate::scan::scan_int;
+    use crate::token::Category::*;
+
+    fn tokenizer(input: &str) -> Tokenizer<std::vec::IntoIter<String>> {
+        let lines: Vec<String> = input.lines().map(|s| s.to_owned()).collect();
+        let mut tokenizer = Tokenizer::new(lines.into_iter());
+        tokenizer.catcode('\u{7f}', Cat15);
+        tokenizer
+    }
+
+    /// Read a number in braces, failing on invalid characters anywhere in the input.
+    fn braced_number(input: &str) -> Result<i32, TextileError> {
+        if let Some(error) = tokenizer(input).find_map(|t| TextileError::from_token(&t)) {
+            return Err(error);
+        }
+        let argument = tokenizer(input).read_argument()?;
+        scan_int(&mut argument.into_iter().peekable())
+    }
+
+    #[test]
+    fn error_sources() {
+        assert_eq!(braced_number("{42}"), Ok(42));
+        assert_eq!(
+            braced_number("{x}"),
+            Err(TextileError::Expansion(ExpansionError::MissingNumber))
+        );
+        assert_eq!(
+            braced_number("{42"),
+            Err(TextileError::Syntax(Span::new(1, 4, 4)))
+        );
+        assert_eq!(
+            braced_number("{4\u{7f}2}"),
+            Err(TextileError::Diagnostic(
+                OtherToken::InvalidCharacter('\u{7f}'),
+                Span::new(1, 2, 2)
+            ))
+        );
+    }
+
+    #[test]
+    fn error_messages() {
+        let error = TextileError::from(ExpansionError::NumberTooBig);
+        assert_eq!(error.to_string(), "Number Too Big");
+        assert!(error.source().is_some());
+        let error = TextileError::Syntax(Span::new(3, 1, 2));
+        assert_eq!(error.to_string(), "Unexpected Input at 3:1-2");
+        assert!(error.source().is_none());
+        assert_eq!(
+            TextileError::from_token(&Token::Other(OtherToken::Comment("x".into()), Span::any())),
+            None
+        );
+    }
+}
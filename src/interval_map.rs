@@ -2,9 +2,19 @@ use std::ops::Range;
 
 use num::{Bounded, Num};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct IntIntervalMap<Idx, V> {
     intervals: Vec<(Idx, V)>,
+    /// Value of all indices if the map has no intervals
+    base: V,
+}
+
+/// Maps are equal if their intervals are equal. The base value only matters for maps
+/// without intervals, as it is not used otherwise.
+impl<Idx: PartialEq, V: PartialEq> PartialEq for IntIntervalMap<Idx, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.intervals == other.intervals && (!self.intervals.is_empty() || self.base == other.base)
+    }
 }
 
 impl<Idx, V> IntervalMap<Idx, V> for IntIntervalMap<Idx, V>
@@ -13,6 +23,14 @@ where
     V: Copy + PartialEq,
 {
     fn assign(&mut self, range: Range<Idx>, new_value: V) {
+        debug_assert!(range.start <= range.end, "decreasing range in assignment");
+        if range.start >= range.end {
+            return;
+        }
+        if self.intervals.is_empty() {
+            self.intervals.push((Idx::max_value(), self.base));
+        }
+
         let lower_thresholds: Vec<Idx> = [Idx::min_value()]
             .iter()
             .chain(self.intervals.iter().map(|(idx, _)| idx))
@@ -21,7 +39,8 @@ where
 
         let drain = self.intervals.drain(..);
 
-        let mut result = vec![];
+        // an assignment splits at most one interval into three
+        let mut result = Vec::with_capacity(lower_thresholds.len() + 2);
 
         for (lower, (upper, value)) in lower_thresholds.iter().zip(drain) {
             let start_in = range.start >= *lower;
@@ -61,45 +80,107 @@ where
         }
         self.intervals = result;
         self.defrag();
+        debug_assert_eq!(self.validate(), Ok(()));
     }
 
     fn assign_single(&mut self, single: Idx, value: V) {
-        self.assign(single..single + Idx::one(), value);
+        // repeated assignments of the same value are common, so avoid rebuilding the map
+        if self.get(single) != value {
+            self.assign(single..single + Idx::one(), value);
+        }
     }
 
     fn get(&self, index: Idx) -> V {
-        let mut last = Idx::min_value();
-        for (i, v) in self.intervals.iter() {
-            if index >= last && index < *i {
-                return *v;
-            }
-            last = *i;
-        }
+        // the upper bounds are ascending, so the interval can be found by binary search
+        let i = self.intervals.partition_point(|(upper, _)| *upper <= index);
         self.intervals
-            .iter()
-            .last()
-            .expect("index out of bounds, check your implementation of the Bounded trait!")
-            .1
+            .get(i)
+            .or_else(|| self.intervals.last())
+            .map_or(self.base, |(_, value)| *value)
     }
 }
 
 impl<Idx, V> IntIntervalMap<Idx, V>
 where
-    Idx: Bounded,
+    Idx: Bounded + PartialEq,
     V: PartialEq,
 {
-    pub fn new(value: V) -> Self {
+    pub fn new(value: V) -> Self
+    where
+        V: Clone,
+    {
         IntIntervalMap {
-            intervals: vec![(Idx::max_value(), value)],
+            intervals: vec![(Idx::max_value(), value.clone())],
+            base: value,
+        }
+    }
+
+    /// Number of intervals the map is currently split into.
+    #[cfg(test)]
+    pub fn interval_count(&self) -> usize {
+        self.intervals.len()
+    }
+
+    /// Build a map from raw intervals, which are not checked.
+    ///
+    /// If there are no intervals, all indices have the value `base`.
+    #[cfg(test)]
+    pub fn from_intervals(intervals: Vec<(Idx, V)>, base: V) -> Self {
+        IntIntervalMap { intervals, base }
+    }
+
+    /// Check that the intervals are non-empty, ascending, cover all indices
+    /// and that no neighbouring intervals have the same value.
+    pub fn validate(&self) -> Result<(), String>
+    where
+        Idx: PartialOrd,
+    {
+        match self.intervals.last() {
+            None => return Err("the map has no intervals".into()),
+            Some((upper, _)) if *upper != Idx::max_value() => {
+                return Err("the last interval does not end at the maximum index".into())
+            }
+            _ => (),
+        }
+        if self.intervals[0].0 == Idx::min_value() {
+            return Err("interval 0 is empty".into());
+        }
+        for (i, pair) in self.intervals.windows(2).enumerate() {
+            if pair[1].0 <= pair[0].0 {
+                return Err(format!(
+                    "interval {} does not end after interval {}",
+                    i + 1,
+                    i
+                ));
+            }
+            if pair[1].1 == pair[0].1 {
+                return Err(format!("intervals {} and {} have the same value", i, i + 1));
+            }
         }
+        Ok(())
     }
 
+    /// Number of intervals the map can hold without reallocating.
+    #[cfg(test)]
+    pub fn capacity(&self) -> usize {
+        self.intervals.capacity()
+    }
+
+    /// Release memory not needed for the current intervals.
+    pub fn shrink_to_fit(&mut self) {
+        self.intervals.shrink_to_fit();
+    }
+
+    /// Remove empty intervals and merge neighbouring intervals of equal value.
     fn defrag(&mut self) {
-        let mut result = vec![];
+        let mut result: Vec<(Idx, V)> = Vec::with_capacity(self.intervals.len());
         let drain = self.intervals.drain(..);
         for (upper, value) in drain {
             match result.last_mut() {
+                // the interval is empty, as it ends where its predecessor ends
+                Some((u, _)) if *u == upper => (),
                 Some((u, v)) if v == &value => *u = upper,
+                None if upper == Idx::min_value() => (),
                 _ => result.push((upper, value)),
             }
         }
@@ -108,6 +189,62 @@ where
     }
 }
 
+impl<Idx, V> IntIntervalMap<Idx, V>
+where
+    Idx: Copy + PartialOrd + Bounded,
+{
+    /// The intervals of the map in ascending order, together with their values.
+    pub fn iter(&self) -> impl Iterator<Item = (Range<Idx>, &V)> {
+        let lower_bounds =
+            std::iter::once(Idx::min_value()).chain(self.intervals.iter().map(|(i, _)| *i));
+        lower_bounds
+            .zip(self.intervals.iter())
+            .map(|(lower, (upper, value))| (lower..*upper, value))
+    }
+
+    /// The intervals overlapping `query` in ascending order, clipped to `query`.
+    pub fn range(&self, query: Range<Idx>) -> impl Iterator<Item = (Range<Idx>, &V)> {
+        self.iter().filter_map(move |(interval, value)| {
+            let start = if interval.start < query.start {
+                query.start
+            } else {
+                interval.start
+            };
+            let end = if interval.end > query.end {
+                query.end
+            } else {
+                interval.end
+            };
+            if start < end {
+                Some((start..end, value))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The half-open interval `index` falls in, together with its value.
+    ///
+    /// Like `get`, the index `Idx::max_value()` is treated as belonging to the last interval.
+    pub fn get_range(&self, index: Idx) -> (Range<Idx>, &V) {
+        let mut last = Idx::min_value();
+        for (i, v) in self.intervals.iter() {
+            if index >= last && index < *i {
+                return (last..*i, v);
+            }
+            last = *i;
+        }
+        let lower = match self.intervals.len() {
+            0 | 1 => Idx::min_value(),
+            n => self.intervals[n - 2].0,
+        };
+        match self.intervals.last() {
+            Some((upper, value)) => (lower..*upper, value),
+            None => (Idx::min_value()..Idx::max_value(), &self.base),
+        }
+    }
+}
+
 pub trait IntervalMap<Idx, V>
 where
     Idx: Copy + PartialOrd,
@@ -115,6 +252,12 @@ where
 {
     fn get(&self, index: Idx) -> V;
 
+    /// Assign `new_value` to all indices in `range`.
+    ///
+    /// This takes time linear in the number of intervals, as the intervals are rebuilt.
+    /// The memory used is proportional to the number of intervals after the assignment.
+    /// Assigning to an empty range does nothing. Decreasing ranges are treated as empty,
+    /// but panic in debug builds.
     fn assign(&mut self, range: Range<Idx>, new_value: V);
 
     fn assign_single(&mut self, single: Idx, value: V);
@@ -162,4 +305,164 @@ mod test {
         assert_eq!('b', map.get(30));
         assert_eq!('z', map.get(31));
     }
+
+    #[test]
+    fn alternating_assignments_stay_compact() {
+        let mut map = IntIntervalMap::<u32, char>::new('o');
+        map.assign(97..123, 'l');
+        map.assign_single(92, 'e');
+        let initial = map.interval_count();
+
+        for i in 0..1000 {
+            let value = if i % 2 == 0 { 'a' } else { 'o' };
+            map.assign_single(92, value);
+            map.assign_single(97 + i % 3, value);
+        }
+        map.assign_single(92, 'e');
+        map.assign_single(97, 'l');
+        map.assign_single(98, 'l');
+        map.assign_single(99, 'l');
+
+        assert_eq!(initial, map.interval_count());
+        assert_eq!('e', map.get(92));
+        assert_eq!('l', map.get(97));
+        assert_eq!('o', map.get(93));
+    }
+
+    #[test]
+    fn assign_at_lower_bound() {
+        let mut map = IntIntervalMap::<u8, char>::new('a');
+        map.assign(0..5, 'b');
+        map.assign(0..5, 'a');
+        assert_eq!(1, map.interval_count());
+        assert_eq!('a', map.get(0));
+    }
+
+    #[test]
+    fn range_of_index() {
+        let mut map = IntIntervalMap::<u8, char>::new('a');
+        map.assign(10..20, 'b');
+        assert_eq!((10..20, &'b'), map.get_range(15));
+        assert_eq!((10..20, &'b'), map.get_range(10));
+        assert_eq!((0..10, &'a'), map.get_range(0));
+        assert_eq!((20..255, &'a'), map.get_range(20));
+        assert_eq!((20..255, &'a'), map.get_range(255));
+    }
+
+    #[test]
+    fn iterate_intervals() {
+        let mut map = IntIntervalMap::<u8, char>::new('a');
+        map.assign(10..20, 'b');
+        let intervals: Vec<_> = map.iter().collect();
+        assert_eq!(
+            intervals,
+            vec![(0..10, &'a'), (10..20, &'b'), (20..255, &'a')]
+        );
+    }
+
+    #[test]
+    fn capacity_stays_bounded() {
+        let mut map = IntIntervalMap::<u32, char>::new('a');
+        for i in 0..10_000 {
+            let value = if i % 3 == 0 { 'a' } else { 'b' };
+            map.assign_single(i % 200, value);
+            assert!(map.capacity() <= map.interval_count() + 4);
+        }
+        map.shrink_to_fit();
+        assert_eq!(map.capacity(), map.interval_count());
+    }
+
+    #[test]
+    fn assign_empty_range() {
+        let mut map = IntIntervalMap::<u8, char>::new('a');
+        map.assign(10..20, 'b');
+        let before = map.clone();
+        map.assign(5..5, 'x');
+        assert_eq!(map, before);
+        map.assign(15..15, 'x');
+        assert_eq!(map, before);
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic)]
+    fn assign_decreasing_range() {
+        let mut map = IntIntervalMap::<u8, char>::new('a');
+        map.assign(10..20, 'b');
+        let before = map.clone();
+        #[allow(clippy::reversed_empty_ranges)]
+        map.assign(5..3, 'x');
+        assert_eq!(map, before);
+    }
+
+    #[test]
+    fn validate_intervals() {
+        let mut map = IntIntervalMap::<u8, char>::new('a');
+        map.assign(10..20, 'b');
+        map.assign(15..30, 'a');
+        assert_eq!(map.validate(), Ok(()));
+
+        let unmerged = IntIntervalMap::<u8, char>::from_intervals(vec![(10, 'a'), (255, 'a')], 'a');
+        assert!(unmerged.validate().is_err());
+        let unsorted =
+            IntIntervalMap::<u8, char>::from_intervals(vec![(20, 'a'), (10, 'b'), (255, 'c')], 'a');
+        assert!(unsorted.validate().is_err());
+        let short = IntIntervalMap::<u8, char>::from_intervals(vec![(10, 'a')], 'a');
+        assert!(short.validate().is_err());
+        assert!(IntIntervalMap::<u8, char>::from_intervals(vec![], 'a')
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn equality_ignores_unused_base() {
+        let mut map = IntIntervalMap::<u8, char>::new('a');
+        map.assign(0..255, 'b');
+        assert_eq!(map, IntIntervalMap::new('b'));
+        assert_ne!(map, IntIntervalMap::new('a'));
+
+        let empty = IntIntervalMap::<u8, char>::from_intervals(vec![], 'a');
+        assert_eq!(empty, IntIntervalMap::from_intervals(vec![], 'a'));
+        assert_ne!(empty, IntIntervalMap::from_intervals(vec![], 'b'));
+    }
+
+    #[test]
+    fn empty_map_uses_base() {
+        let mut map = IntIntervalMap::<u8, char>::from_intervals(vec![], 'a');
+        assert_eq!(map.get(0), 'a');
+        assert_eq!(map.get(255), 'a');
+        assert_eq!(map.get_range(10), (0..255, &'a'));
+        map.assign(10..20, 'b');
+        assert_eq!(map.validate(), Ok(()));
+        assert_eq!(map.get(5), 'a');
+        assert_eq!(map.get(10), 'b');
+        assert_eq!(map.get(20), 'a');
+    }
+
+    #[test]
+    fn clipped_range() {
+        let mut map = IntIntervalMap::<u32, char>::new('o');
+        map.assign(65..91, 'l');
+        map.assign(97..123, 'l');
+        map.assign(92..93, 'e');
+        map.assign(200..300, 'x');
+        let intervals: Vec<_> = map.range(0..128).collect();
+        assert_eq!(
+            intervals,
+            vec![
+                (0..65, &'o'),
+                (65..91, &'l'),
+                (91..92, &'o'),
+                (92..93, &'e'),
+                (93..97, &'o'),
+                (97..123, &'l'),
+                (123..128, &'o'),
+            ]
+        );
+        let intervals: Vec<_> = map.range(100..250).collect();
+        assert_eq!(
+            intervals,
+            vec![(100..123, &'l'), (123..200, &'o'), (200..250, &'x')]
+        );
+        assert_eq!(map.range(70..70).count(), 0);
+    }
 }
@@ -1,6 +1,30 @@
+//! A tokenizer for TeX input with tools for processing its tokens.
+//!
+//! The tokenizer turns input lines into tokens following the rules of
+//! chapter 8 of the TeXbook. Its behaviour can be changed during tokenization
+//! through the methods of `TokenizerInteraction`:
+//!
+//! ```
+//! use tex_parser::token::{Category, Span, Token, Tokenizer, TokenizerInteraction};
+//!
+//! let mut tokenizer = Tokenizer::new("\\foo~".lines().map(|l| l.to_owned()));
+//! assert_eq!(
+//!     tokenizer.next(),
+//!     Some(Token::ControlSequence("foo".into(), Span::new(1, 0, 3)))
+//! );
+//! tokenizer.catcode('~', Category::Cat12);
+//! assert_eq!(tokenizer.next(), Some(Token::Character('~', Category::Cat12)));
+//! ```
+//!
+//! Token lists can be processed further with the functions in `token_list`,
+//! numbers are read by `scan` and macro definitions are built in `macros`.
+//! Their fallible functions return the common `error::TextileError`.
+pub mod error;
 mod interval_map;
 pub mod macros;
+pub mod scan;
 pub mod token;
+pub mod token_list;
 
 #[cfg(test)]
 mod test;
@@ -1,6 +1,8 @@
+use crate::error::TextileError;
 use crate::token::*;
 /// Implements a TeX expansion processor.
 use std::error::Error;
+use std::iter::Peekable;
 
 /// A location in the input file.
 #[derive(Debug, Clone)]
@@ -9,29 +11,41 @@ pub struct Span {
     pub start: (usize, usize),
     /// Line *number* and column *index* of the location end.
     pub end: (usize, usize),
+    /// Whether this is the dummy span created by `Span::any()`
+    wildcard: bool,
 }
 
 impl Span {
     pub fn new(start: (usize, usize), end: (usize, usize)) -> Self {
-        Span { start, end }
+        Span {
+            start,
+            end,
+            wildcard: false,
+        }
     }
 
     pub fn extend_to(&mut self, end: (usize, usize)) {
         self.end = end;
     }
 
+    /// Dummy span, which is equal to any other span, like `token::Span::any()`.
     pub fn any() -> Self {
         Span {
             start: (0, 0),
             end: (0, 0),
+            wildcard: true,
         }
     }
+
+    /// Whether this is the dummy span created by `Span::any()`.
+    pub fn is_any(&self) -> bool {
+        self.wildcard
+    }
 }
 
 impl PartialEq for Span {
     fn eq(&self, other: &Span) -> bool {
-        (self.start == other.start && self.end == other.end)
-            || (self.start == (0, 0) && self.end == (0, 0))
+        self.start == other.start && self.end == other.end || self.is_any() || other.is_any()
     }
 }
 
@@ -54,6 +68,12 @@ pub enum ExpansionError {
     ExplicitBracesInParameterText,
     NonConsequitiveParameterNumber,
     InvalidParameterNumber,
+    MissingNumber,
+    NumberTooBig,
+    InvalidCategoryCode,
+    MalformedEncoding,
+    PrefixWithoutDefinition,
+    InvalidCharCode,
 }
 
 impl std::fmt::Display for ExpansionError {
@@ -67,6 +87,12 @@ impl std::fmt::Display for ExpansionError {
                 write!(f, "Non-Consequtive Parameter Number in Parameter Text")
             }
             ExpansionError::InvalidParameterNumber => write!(f, "Invalid Parameter Number"),
+            ExpansionError::MissingNumber => write!(f, "Missing Number"),
+            ExpansionError::NumberTooBig => write!(f, "Number Too Big"),
+            ExpansionError::InvalidCategoryCode => write!(f, "Invalid Category Code"),
+            ExpansionError::MalformedEncoding => write!(f, "Malformed Macro Encoding"),
+            ExpansionError::PrefixWithoutDefinition => write!(f, "Prefix Without Definition"),
+            ExpansionError::InvalidCharCode => write!(f, "Invalid Character Code"),
         }
     }
 }
@@ -86,6 +112,16 @@ impl Error for ExpansionError {
             ExpansionError::InvalidParameterNumber => {
                 "Macro parameter names must be numbers with category code 12!"
             }
+            ExpansionError::MissingNumber => "A number was expected, but no digits were found!",
+            ExpansionError::NumberTooBig => "Numbers must not be greater than 2147483647!",
+            ExpansionError::InvalidCategoryCode => "Category codes must be between 0 and 15!",
+            ExpansionError::MalformedEncoding => "The bytes do not encode a macro definition!",
+            ExpansionError::PrefixWithoutDefinition => {
+                "Prefixes like \\long must be followed by a macro definition!"
+            }
+            ExpansionError::InvalidCharCode => {
+                "Character codes must be between 0 and 1114111, excluding surrogates!"
+            }
         }
     }
 
@@ -95,10 +131,100 @@ impl Error for ExpansionError {
             ExpansionError::ExplicitBracesInParameterText => None,
             ExpansionError::NonConsequitiveParameterNumber => None,
             ExpansionError::InvalidParameterNumber => None,
+            ExpansionError::MissingNumber => None,
+            ExpansionError::NumberTooBig => None,
+            ExpansionError::InvalidCategoryCode => None,
+            ExpansionError::MalformedEncoding => None,
+            ExpansionError::PrefixWithoutDefinition => None,
+            ExpansionError::InvalidCharCode => None,
         }
     }
 }
 
+/// The prefixes of a macro definition, e.g. `\long\global` in `\long\global\def`.
+///
+/// Prefixes are combined like bit flags, e.g. `DefPrefix::LONG | DefPrefix::GLOBAL`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct DefPrefix {
+    bits: u8,
+}
+
+impl DefPrefix {
+    pub const GLOBAL: DefPrefix = DefPrefix { bits: 1 };
+    pub const LONG: DefPrefix = DefPrefix { bits: 2 };
+    pub const OUTER: DefPrefix = DefPrefix { bits: 4 };
+    pub const PROTECTED: DefPrefix = DefPrefix { bits: 8 };
+
+    /// No prefixes at all.
+    pub fn empty() -> Self {
+        DefPrefix::default()
+    }
+
+    /// Whether all prefixes of `other` are set.
+    pub fn contains(self, other: DefPrefix) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Whether no prefix is set.
+    pub fn is_empty(self) -> bool {
+        self.bits == 0
+    }
+
+    /// The prefix named by the control sequence `name`, if any.
+    fn from_name(name: &str) -> Option<DefPrefix> {
+        match name {
+            "global" => Some(DefPrefix::GLOBAL),
+            "long" => Some(DefPrefix::LONG),
+            "outer" => Some(DefPrefix::OUTER),
+            "protected" => Some(DefPrefix::PROTECTED),
+            _ => None,
+        }
+    }
+}
+
+impl std::ops::BitOr for DefPrefix {
+    type Output = DefPrefix;
+
+    fn bitor(self, other: DefPrefix) -> DefPrefix {
+        DefPrefix {
+            bits: self.bits | other.bits,
+        }
+    }
+}
+
+/// Consume the prefixes in front of a macro definition.
+///
+/// Spaces and non-TeX tokens after a prefix are skipped. If any prefix was found,
+/// the next token must be one of \def, \edef, \gdef or \xdef, which is not consumed.
+/// Without a prefix, no tokens are consumed.
+pub fn parse_def_prefixes<I: Iterator<Item = Token>>(
+    tokens: &mut Peekable<I>,
+) -> Result<DefPrefix, TextileError> {
+    let mut prefix = DefPrefix::empty();
+    loop {
+        match tokens.peek() {
+            Some(Token::Character(_, Category::Cat10)) | Some(Token::Other(_, _))
+                if !prefix.is_empty() => {}
+            Some(Token::ControlSequence(name, _)) => match DefPrefix::from_name(name) {
+                Some(p) => prefix = prefix | p,
+                None => break,
+            },
+            _ => break,
+        }
+        tokens.next();
+    }
+    let is_def = match tokens.peek() {
+        Some(Token::ControlSequence(name, _)) => {
+            matches!(name.as_str(), "def" | "edef" | "gdef" | "xdef")
+        }
+        _ => false,
+    };
+    if !prefix.is_empty() && !is_def {
+        return Err(ExpansionError::PrefixWithoutDefinition.into());
+    }
+    Ok(prefix)
+}
+
 impl Macro {
     /// Build parameter tokens from a token list.
     fn parse_parameter_tokens(token_list: Vec<Token>) -> Result<Vec<Token>, ExpansionError> {
@@ -136,24 +262,274 @@ impl Macro {
         control_sequence: Token,
         parameter_text: Vec<Token>,
         replacement_text: Vec<Token>,
-    ) -> Result<Macro, ExpansionError> {
-        let (name, def_start) = match control_sequence {
-            Token::ControlSequence(name, span) => (name, (span.line, span.start)),
-            _ => return Err(ExpansionError::InvalidDefName),
+    ) -> Result<Macro, TextileError> {
+        let (name, cs_span) = match control_sequence {
+            Token::ControlSequence(name, span) => (name, span),
+            _ => return Err(ExpansionError::InvalidDefName.into()),
         };
+        let def_start = (cs_span.line, cs_span.start);
+
+        // the definition ends with the last token of known location
+        let last_span = replacement_text
+            .iter()
+            .rev()
+            .chain(parameter_text.iter().rev())
+            .filter_map(Token::span)
+            .find(|span| !span.is_any())
+            .unwrap_or(&cs_span);
+        let def_end = (last_span.line, last_span.end);
 
         let params = Self::parse_parameter_tokens(parameter_text)?;
         let replacement = Self::parse_parameter_tokens(replacement_text)?;
 
-        let def_end = (0, 0);
         Ok(Macro {
             control_sequence: name,
             parameter_text: params,
             replacement_text: replacement,
-            location: Span {
-                start: def_start,
-                end: def_end,
-            },
+            location: Span::new(def_start, def_end),
+        })
+    }
+}
+
+impl Macro {
+    /// Number of parameters, i.e. the highest parameter number in the parameter text.
+    pub fn arity(&self) -> u8 {
+        self.parameter_text
+            .iter()
+            .filter_map(|t| match t {
+                Token::Parameter(n) => Some(*n),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether the parameter text contains delimiter tokens besides the parameters.
+    ///
+    /// If not, all arguments can be read as single tokens or groups.
+    /// Non-TeX tokens like comments are no delimiters.
+    pub fn has_delimited_params(&self) -> bool {
+        self.parameter_text
+            .iter()
+            .any(|t| !matches!(t, Token::Parameter(_) | Token::Other(_, _)))
+    }
+}
+
+/// Writes the binary encoding of macro definitions.
+///
+/// Numbers are stored as little endian `u64`, strings and lists are prefixed with their length.
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn number(&mut self, n: usize) {
+        self.bytes.extend_from_slice(&(n as u64).to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.number(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    /// Spans are prefixed with 1 for wildcard spans, which have no location, 2 for
+    /// zero-width spans, which only have a line and a column, and 0 otherwise.
+    fn span(&mut self, span: &crate::token::Span) {
+        if span.is_any() {
+            self.bytes.push(1);
+            return;
+        }
+        if span.is_zero_width() {
+            self.bytes.push(2);
+            self.number(span.line);
+            self.number(span.start);
+            return;
+        }
+        self.bytes.push(0);
+        self.number(span.line);
+        self.number(span.start);
+        self.number(span.end);
+    }
+
+    /// Locations are prefixed with 1 for the wildcard location and 0 otherwise.
+    fn location(&mut self, location: &Span) {
+        if location.is_any() {
+            self.bytes.push(1);
+            return;
+        }
+        self.bytes.push(0);
+        self.number(location.start.0);
+        self.number(location.start.1);
+        self.number(location.end.0);
+        self.number(location.end.1);
+    }
+
+    fn tokens(&mut self, tokens: &[Token]) {
+        self.number(tokens.len());
+        for token in tokens {
+            match token {
+                Token::ControlSequence(name, span) => {
+                    self.bytes.push(0);
+                    self.string(name);
+                    self.span(span);
+                }
+                Token::Character(c, cat) => {
+                    self.bytes.push(1);
+                    self.number(*c as usize);
+                    self.bytes.push(*cat as u8);
+                }
+                Token::Parameter(n) => {
+                    self.bytes.push(2);
+                    self.bytes.push(*n);
+                }
+                Token::Other(other, span) => {
+                    self.bytes.push(3);
+                    match other {
+                        OtherToken::Comment(s) => {
+                            self.bytes.push(0);
+                            self.string(s);
+                        }
+                        OtherToken::IgnoredCharacter(c) => {
+                            self.bytes.push(1);
+                            self.number(*c as usize);
+                        }
+                        OtherToken::InvalidCharacter(c) => {
+                            self.bytes.push(2);
+                            self.number(*c as usize);
+                        }
+                        OtherToken::Skipped(s) => {
+                            self.bytes.push(3);
+                            self.string(s);
+                        }
+                        OtherToken::Runaway(s) => {
+                            self.bytes.push(4);
+                            self.string(s);
+                        }
+                    }
+                    self.span(span);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the binary encoding written by `Encoder`.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ExpansionError> {
+        if n > self.bytes.len() {
+            return Err(ExpansionError::MalformedEncoding);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, ExpansionError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn number(&mut self) -> Result<usize, ExpansionError> {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buffer) as usize)
+    }
+
+    fn character(&mut self) -> Result<char, ExpansionError> {
+        std::char::from_u32(self.number()? as u32).ok_or(ExpansionError::MalformedEncoding)
+    }
+
+    fn string(&mut self) -> Result<String, ExpansionError> {
+        let length = self.number()?;
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|_| ExpansionError::MalformedEncoding)
+    }
+
+    fn span(&mut self) -> Result<crate::token::Span, ExpansionError> {
+        match self.byte()? {
+            0 => Ok(crate::token::Span::new(
+                self.number()?,
+                self.number()?,
+                self.number()?,
+            )),
+            1 => Ok(crate::token::Span::any()),
+            2 => Ok(crate::token::Span::at(self.number()?, self.number()?)),
+            _ => Err(ExpansionError::MalformedEncoding),
+        }
+    }
+
+    fn location(&mut self) -> Result<Span, ExpansionError> {
+        match self.byte()? {
+            0 => Ok(Span::new(
+                (self.number()?, self.number()?),
+                (self.number()?, self.number()?),
+            )),
+            1 => Ok(Span::any()),
+            _ => Err(ExpansionError::MalformedEncoding),
+        }
+    }
+
+    fn tokens(&mut self) -> Result<Vec<Token>, ExpansionError> {
+        let length = self.number()?;
+        let mut tokens = vec![];
+        for _ in 0..length {
+            let token = match self.byte()? {
+                0 => Token::ControlSequence(self.string()?, self.span()?),
+                1 => {
+                    let c = self.character()?;
+                    let cat = Category::from_number(i32::from(self.byte()?))
+                        .ok_or(ExpansionError::MalformedEncoding)?;
+                    Token::Character(c, cat)
+                }
+                2 => Token::Parameter(self.byte()?),
+                3 => {
+                    let other = match self.byte()? {
+                        0 => OtherToken::Comment(self.string()?),
+                        1 => OtherToken::IgnoredCharacter(self.character()?),
+                        2 => OtherToken::InvalidCharacter(self.character()?),
+                        3 => OtherToken::Skipped(self.string()?),
+                        4 => OtherToken::Runaway(self.string()?),
+                        _ => return Err(ExpansionError::MalformedEncoding),
+                    };
+                    Token::Other(other, self.span()?)
+                }
+                _ => return Err(ExpansionError::MalformedEncoding),
+            };
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+}
+
+impl Macro {
+    /// Encode the macro definition in a compact binary format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder { bytes: vec![] };
+        encoder.string(&self.control_sequence);
+        encoder.tokens(&self.parameter_text);
+        encoder.tokens(&self.replacement_text);
+        encoder.location(&self.location);
+        encoder.bytes
+    }
+
+    /// Decode a macro definition encoded by `Macro::to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Macro, TextileError> {
+        let mut decoder = Decoder { bytes };
+        let control_sequence = decoder.string()?;
+        let parameter_text = decoder.tokens()?;
+        let replacement_text = decoder.tokens()?;
+        let location = decoder.location()?;
+        if !decoder.bytes.is_empty() {
+            return Err(ExpansionError::MalformedEncoding.into());
+        }
+        Ok(Macro {
+            control_sequence,
+            parameter_text,
+            replacement_text,
+            location,
         })
     }
 }
@@ -178,25 +554,42 @@ mod expansion_test {
         assert!(Macro::define(cs, param, replacement).is_ok());
     }
 
+    #[test]
+    fn define_null_control_sequence() {
+        let cs = ControlSequence(String::new(), crate::token::Span::new(1, 0, 0));
+        assert!(cs.is_null_cs());
+        let null = Macro::define(cs, vec![], tokens("x")).unwrap();
+        assert_eq!(null.control_sequence, "");
+        assert_eq!(Macro::from_bytes(&null.to_bytes()), Ok(null));
+    }
+
     #[test]
     fn define_macro_with_args() {
         let cs = ControlSequence("PickTwo".to_owned(), crate::token::Span::any());
         let param = tokens("#1abc#2");
         let replacement = tokens("(#1,#2)");
         assert_eq!(
-            Err(ExpansionError::InvalidParameterNumber),
+            Err(TextileError::Expansion(
+                ExpansionError::InvalidParameterNumber
+            )),
             Macro::define(cs.clone(), tokens("#0"), vec![])
         );
         assert_eq!(
-            Err(ExpansionError::ExplicitBracesInParameterText),
+            Err(TextileError::Expansion(
+                ExpansionError::ExplicitBracesInParameterText
+            )),
             Macro::define(cs.clone(), tokens("#1{#2}"), vec![])
         );
         assert_eq!(
-            Err(ExpansionError::InvalidParameterNumber),
+            Err(TextileError::Expansion(
+                ExpansionError::InvalidParameterNumber
+            )),
             Macro::define(cs.clone(), tokens("#{#2"), vec![])
         );
         assert_eq!(
-            Err(ExpansionError::InvalidParameterNumber),
+            Err(TextileError::Expansion(
+                ExpansionError::InvalidParameterNumber
+            )),
             Macro::define(cs.clone(), tokens("#abc#2"), vec![])
         );
         assert_eq!(
@@ -221,4 +614,163 @@ mod expansion_test {
             Macro::define(cs, param, replacement).expect("could not define macro!")
         );
     }
+
+    #[test]
+    fn macro_bytes_round_trip() {
+        let cs = ControlSequence("PickTwo".to_owned(), crate::token::Span::new(3, 1, 8));
+        let mut replacement = tokens("(#1,#2) \\relax%");
+        replacement.push(Other(
+            crate::token::OtherToken::Runaway("^^f".into()),
+            crate::token::Span::new(1, 2, 4),
+        ));
+        let definition =
+            Macro::define(cs, tokens("#1abc#2"), replacement).expect("could not define macro!");
+
+        let bytes = definition.to_bytes();
+        let decoded = Macro::from_bytes(&bytes).expect("could not decode macro!");
+        assert_eq!(decoded, definition);
+        assert_eq!(decoded.location.start, (3, 1));
+        assert_eq!(decoded.to_bytes(), bytes);
+
+        assert_eq!(
+            Err(TextileError::Expansion(ExpansionError::MalformedEncoding)),
+            Macro::from_bytes(&bytes[..bytes.len() - 1])
+        );
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert_eq!(
+            Err(TextileError::Expansion(ExpansionError::MalformedEncoding)),
+            Macro::from_bytes(&extended)
+        );
+    }
+
+    #[test]
+    fn macro_bytes_wildcard_spans() {
+        let cs = ControlSequence("wild".to_owned(), crate::token::Span::new(1, 0, 5));
+        let replacement = vec![
+            ControlSequence("relax".into(), crate::token::Span::any()),
+            ControlSequence("inserted".into(), crate::token::Span::at(1, 6)),
+            Other(
+                crate::token::OtherToken::Comment("x".into()),
+                crate::token::Span::new(1, 6, 8),
+            ),
+        ];
+        let definition = Macro::define(cs, vec![], replacement).expect("could not define macro!");
+        let decoded = Macro::from_bytes(&definition.to_bytes()).expect("could not decode macro!");
+        assert_eq!(decoded, definition);
+        let spans: Vec<_> = decoded
+            .replacement_text
+            .iter()
+            .filter_map(Token::span)
+            .collect();
+        assert!(spans[0].is_any());
+        assert!(spans[1].is_zero_width());
+        assert_eq!(*spans[1], crate::token::Span::at(1, 6));
+        assert!(!spans[2].is_any() && !spans[2].is_zero_width());
+        assert_eq!(*spans[2], crate::token::Span::new(1, 6, 8));
+    }
+
+    #[test]
+    fn wildcard_locations() {
+        let any = crate::macros::Span::any();
+        let origin = crate::macros::Span::new((0, 0), (0, 0));
+        let real = crate::macros::Span::new((1, 0), (1, 5));
+        // the wildcard matches from either side, real locations never match by accident
+        assert_eq!(any, real);
+        assert_eq!(real, any);
+        assert_eq!(origin, any);
+        assert_ne!(origin, real);
+        assert_ne!(real, origin);
+        assert!(any.is_any() && !origin.is_any());
+
+        let cs = ControlSequence("wild".to_owned(), crate::token::Span::any());
+        let mut definition = Macro::define(cs, vec![], vec![]).expect("could not define macro!");
+        for location in [any, origin] {
+            let wildcard = location.is_any();
+            definition.location = location;
+            let decoded =
+                Macro::from_bytes(&definition.to_bytes()).expect("could not decode macro!");
+            assert_eq!(decoded.location.is_any(), wildcard);
+        }
+    }
+
+    #[test]
+    fn macro_location() {
+        // the definition of `\test` in the second line, split into its parts
+        let defined = |source: &str| {
+            let definition = tokens(&format!("%\n{}", source));
+            let open = definition
+                .iter()
+                .position(|t| *t == Character('{', Cat1))
+                .unwrap();
+            Macro::define(
+                definition[1].clone(),
+                definition[2..open].to_vec(),
+                definition[open + 1..definition.len() - 1].to_vec(),
+            )
+            .expect("could not define macro!")
+            .location
+        };
+        let location = defined("\\test#1{a \\b c}");
+        assert_eq!(location.start, (2, 0));
+        assert_eq!(location.end, (2, 12));
+        assert_eq!(defined("\\test\\x#1{abc}").end, (2, 6));
+        assert_eq!(defined("\\test{abc}").end, (2, 4));
+    }
+
+    #[test]
+    fn def_prefixes() {
+        let mut input = tokens("\\long \\global\\def\\a").into_iter().peekable();
+        let prefix = parse_def_prefixes(&mut input).expect("could not parse prefixes!");
+        assert_eq!(prefix, DefPrefix::LONG | DefPrefix::GLOBAL);
+        assert!(prefix.contains(DefPrefix::LONG));
+        assert!(!prefix.contains(DefPrefix::OUTER));
+        assert_eq!(
+            input.next(),
+            Some(ControlSequence("def".into(), crate::token::Span::any()))
+        );
+
+        let mut input = tokens("\\def").into_iter().peekable();
+        assert_eq!(parse_def_prefixes(&mut input), Ok(DefPrefix::empty()));
+
+        let mut input = tokens("\\relax").into_iter().peekable();
+        assert_eq!(parse_def_prefixes(&mut input), Ok(DefPrefix::empty()));
+
+        // spaces are only skipped after a prefix
+        let space = Character(' ', Cat10);
+        let mut input = vec![
+            space.clone(),
+            ControlSequence("def".into(), crate::token::Span::any()),
+        ]
+        .into_iter()
+        .peekable();
+        assert_eq!(parse_def_prefixes(&mut input), Ok(DefPrefix::empty()));
+        assert_eq!(input.next(), Some(space));
+
+        let mut input = tokens("\\protected\\outer\\relax").into_iter().peekable();
+        assert_eq!(
+            parse_def_prefixes(&mut input),
+            Err(TextileError::Expansion(
+                ExpansionError::PrefixWithoutDefinition
+            ))
+        );
+    }
+
+    #[test]
+    fn macro_arity() {
+        let cs = ControlSequence("PickTwo".to_owned(), crate::token::Span::any());
+        let pick_two = Macro::define(cs.clone(), tokens("#1abc#2"), tokens("(#1,#2)"))
+            .expect("could not define macro!");
+        assert_eq!(pick_two.arity(), 2);
+        assert!(pick_two.has_delimited_params());
+
+        let undelimited = Macro::define(cs.clone(), tokens("#1#2#3"), tokens("#3"))
+            .expect("could not define macro!");
+        assert_eq!(undelimited.arity(), 3);
+        assert!(!undelimited.has_delimited_params());
+
+        let constant = Macro::define(cs, vec![], tokens("x")).expect("could not define macro!");
+        assert_eq!(constant.arity(), 0);
+        assert!(!constant.has_delimited_params());
+    }
 }
@@ -1,4 +1,5 @@
 use tex_parser::token::*;
+use tex_parser::token_list::dump_tokens;
 
 fn main() {
     let tokenizer = Tokenizer::new(
@@ -7,5 +8,5 @@ fn main() {
             .map(|l| l.to_owned()),
     );
     let result: Vec<Token> = tokenizer.collect();
-    eprintln!("{:#?}", result);
+    eprint!("{}", dump_tokens(&result));
 }
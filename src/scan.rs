@@ -0,0 +1,297 @@
+/// Scanning of TeX quantities from token lists, as described in chapter 24 of the texbook.
+use crate::error::TextileError;
+use crate::macros::ExpansionError;
+use crate::token::*;
+use std::iter::Peekable;
+
+/// Peek at the next TeX token, dropping non-TeX tokens like comments.
+fn peek_tex<I: Iterator<Item = Token>>(tokens: &mut Peekable<I>) -> Option<&Token> {
+    while let Some(Token::Other(_, _)) = tokens.peek() {
+        tokens.next();
+    }
+    tokens.peek()
+}
+
+/// Value of `token` as a digit of a number with base `radix`, if possible.
+///
+/// Hexadecimal digits greater than 9 must be uppercase letters of category 11 or 12.
+fn digit_value(token: &Token, radix: u32) -> Option<u32> {
+    match token {
+        Token::Character(c, Category::Cat12) if c.is_ascii_digit() => {
+            c.to_digit(10).filter(|d| *d < radix)
+        }
+        Token::Character(c, Category::Cat11) | Token::Character(c, Category::Cat12)
+            if radix == 16 && ('A'..='F').contains(c) =>
+        {
+            c.to_digit(16)
+        }
+        _ => None,
+    }
+}
+
+/// Scan the digits of a number with base `radix`.
+fn scan_digits<I: Iterator<Item = Token>>(
+    tokens: &mut Peekable<I>,
+    radix: u32,
+) -> Result<i64, ExpansionError> {
+    let mut value: i64 = 0;
+    let mut digits = 0;
+    while let Some(digit) = peek_tex(tokens).and_then(|t| digit_value(t, radix)) {
+        tokens.next();
+        value = value * i64::from(radix) + i64::from(digit);
+        if value > i64::from(i32::MAX) {
+            return Err(ExpansionError::NumberTooBig);
+        }
+        digits += 1;
+    }
+    if digits == 0 {
+        return Err(ExpansionError::MissingNumber);
+    }
+    Ok(value)
+}
+
+/// Scan an alphabetic constant, i.e. a character or single-character control sequence
+/// following a backtick.
+fn scan_char_constant<I: Iterator<Item = Token>>(
+    tokens: &mut Peekable<I>,
+) -> Result<i64, ExpansionError> {
+    let chr = match peek_tex(tokens) {
+        Some(Token::Character(c, _)) => *c,
+        Some(Token::ControlSequence(name, _)) if name.chars().count() == 1 => {
+            name.chars().next().unwrap()
+        }
+        _ => return Err(ExpansionError::MissingNumber),
+    };
+    tokens.next();
+    Ok(i64::from(chr as u32))
+}
+
+/// Scan an integer as described on p. 269 of the texbook.
+///
+/// Leading signs and spaces are consumed, followed by the number. It may be given
+/// in decimal, as octal number after `'`, as hexadecimal number after `"` or as
+/// the character code of a character or a control symbol after a backtick.
+/// The number is terminated by the first non-digit. If it is a \relax control sequence
+/// or a space, it is consumed as well.
+pub fn scan_int<I: Iterator<Item = Token>>(tokens: &mut Peekable<I>) -> Result<i32, TextileError> {
+    let mut negative = false;
+    loop {
+        match peek_tex(tokens) {
+            Some(Token::Character('-', Category::Cat12)) => negative = !negative,
+            Some(Token::Character('+', Category::Cat12))
+            | Some(Token::Character(_, Category::Cat10)) => (),
+            _ => break,
+        }
+        tokens.next();
+    }
+
+    let value = match peek_tex(tokens) {
+        Some(Token::Character('\'', Category::Cat12)) => {
+            tokens.next();
+            scan_digits(tokens, 8)?
+        }
+        Some(Token::Character('"', Category::Cat12)) => {
+            tokens.next();
+            scan_digits(tokens, 16)?
+        }
+        Some(Token::Character('`', Category::Cat12)) => {
+            tokens.next();
+            scan_char_constant(tokens)?
+        }
+        _ => scan_digits(tokens, 10)?,
+    };
+
+    match peek_tex(tokens) {
+        Some(Token::ControlSequence(name, _)) if name == "relax" => {
+            tokens.next();
+        }
+        Some(Token::Character(_, Category::Cat10)) => {
+            tokens.next();
+        }
+        _ => (),
+    };
+
+    Ok(if negative { -value } else { value } as i32)
+}
+
+/// Parse the arguments of a \catcode assignment.
+///
+/// `tokens` must start right after the \catcode control sequence, i.e. with the
+/// character code, followed by an optional equals sign and the category code.
+fn scan_catcode_assignment<I: Iterator<Item = Token>>(
+    tokens: &mut Peekable<I>,
+) -> Result<(char, Category), TextileError> {
+    let code = scan_int(tokens)?;
+    while let Some(Token::Character(_, Category::Cat10)) = peek_tex(tokens) {
+        tokens.next();
+    }
+    if let Some(Token::Character('=', Category::Cat12)) = peek_tex(tokens) {
+        tokens.next();
+    }
+    let category =
+        Category::from_number(scan_int(tokens)?).ok_or(ExpansionError::InvalidCategoryCode)?;
+    let chr = std::char::from_u32(code as u32).ok_or(ExpansionError::InvalidCharCode)?;
+    Ok((chr, category))
+}
+
+/// Parse the arguments of a \catcode assignment and apply it to `tokenizer`.
+///
+/// `tokens` must start right after the \catcode control sequence, i.e. with the
+/// character code, followed by an optional equals sign and the category code.
+pub fn apply_catcode_assignment<I: Iterator<Item = Token>>(
+    tokens: &mut Peekable<I>,
+    tokenizer: &mut dyn TokenizerInteraction,
+) -> Result<(), TextileError> {
+    let (chr, category) = scan_catcode_assignment(tokens)?;
+    tokenizer.catcode(chr, category);
+    Ok(())
+}
+
+/// Find the \catcode assignments in `tokens` without applying them.
+///
+/// The assignments are returned in the order of their appearance. Malformed
+/// assignments are skipped. As `tokens` are not expanded, assignments hidden in
+/// macros are not found.
+pub fn extract_catcode_changes(tokens: &[Token]) -> Vec<(char, Category)> {
+    let mut stream = tokens.iter().cloned().peekable();
+    let mut changes = vec![];
+    while let Some(token) = stream.next() {
+        match token {
+            Token::ControlSequence(name, _) if name == "catcode" => {
+                if let Ok(change) = scan_catcode_assignment(&mut stream) {
+                    changes.push(change);
+                }
+            }
+            _ => (),
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod scan_test {
+    use crate::macros::ExpansionError;
+    use crate::scan::*;
+    use crate::token::{Category::*, Token::*};
+
+    fn tokens(input: &str) -> Vec<Token> {
+        let mut tokenizer = Tokenizer::new(input.lines().map(|s| s.to_owned()));
+        // disable endlinechar
+        tokenizer.set_endlinechar(std::char::from_u32(256).unwrap());
+        tokenizer.collect()
+    }
+
+    fn scan(input: &str) -> (Result<i32, TextileError>, Vec<Token>) {
+        let mut stream = tokens(input).into_iter().peekable();
+        let result = scan_int(&mut stream);
+        (result, stream.collect())
+    }
+
+    #[test]
+    fn scan_decimal() {
+        assert_eq!(scan("42"), (Ok(42), vec![]));
+        assert_eq!(scan(" - -+ 007x"), (Ok(7), vec![Character('x', Cat11)]));
+        assert_eq!(scan("-12,"), (Ok(-12), vec![Character(',', Cat12)]));
+        assert_eq!(
+            scan("x"),
+            (
+                Err(TextileError::Expansion(ExpansionError::MissingNumber)),
+                vec![Character('x', Cat11)]
+            )
+        );
+        assert_eq!(scan("2147483647").0, Ok(i32::MAX));
+        assert_eq!(
+            scan("2147483648").0,
+            Err(TextileError::Expansion(ExpansionError::NumberTooBig))
+        );
+    }
+
+    #[test]
+    fn scan_terminator() {
+        assert_eq!(scan("123\\relax{"), (Ok(123), vec![Character('{', Cat1)]));
+        assert_eq!(scan("123 x"), (Ok(123), vec![Character('x', Cat11)]));
+        // only one \relax is consumed
+        assert_eq!(
+            scan("123\\relax\\relax"),
+            (Ok(123), vec![ControlSequence("relax".into(), Span::any())])
+        );
+    }
+
+    #[test]
+    fn scan_radix() {
+        assert_eq!(scan("'176x"), (Ok(126), vec![Character('x', Cat11)]));
+        assert_eq!(
+            scan("'8").0,
+            Err(TextileError::Expansion(ExpansionError::MissingNumber))
+        );
+        assert_eq!(scan("\"7E "), (Ok(126), vec![]));
+        assert_eq!(scan("-\"FF"), (Ok(-255), vec![]));
+        assert_eq!(scan("`~"), (Ok(126), vec![]));
+        assert_eq!(scan("`\\~ "), (Ok(126), vec![]));
+        assert_eq!(scan("`\\~x"), (Ok(126), vec![Character('x', Cat11)]));
+        assert_eq!(
+            scan("`\\relax").0,
+            Err(TextileError::Expansion(ExpansionError::MissingNumber))
+        );
+    }
+
+    #[test]
+    fn scan_letters() {
+        assert_eq!(scan("\"1A"), (Ok(26), vec![]));
+        assert_eq!(scan("1A"), (Ok(1), vec![Character('A', Cat11)]));
+        // only uppercase hex digits are allowed
+        assert_eq!(scan("\"1a"), (Ok(1), vec![Character('a', Cat11)]));
+        assert_eq!(
+            scan("\"a"),
+            (
+                Err(TextileError::Expansion(ExpansionError::MissingNumber)),
+                vec![Character('a', Cat11)]
+            )
+        );
+        assert_eq!(scan("\"FG"), (Ok(15), vec![Character('G', Cat11)]));
+    }
+
+    #[test]
+    fn catcode_assignment() {
+        for assignment in &["`\\~=12", "`~ = 12", "126=12", "\"7E=12", "'176 12"] {
+            let mut tokenizer = Tokenizer::new(vec!["~".to_owned()].into_iter());
+            let mut stream = tokens(assignment).into_iter().peekable();
+            assert_eq!(
+                apply_catcode_assignment(&mut stream, &mut tokenizer),
+                Ok(())
+            );
+            assert_eq!(stream.next(), None);
+            assert_eq!(tokenizer.next(), Some(Character('~', Cat12)));
+        }
+
+        let mut tokenizer = Tokenizer::new(Vec::<String>::new().into_iter());
+        let mut stream = tokens("`~=16").into_iter().peekable();
+        assert_eq!(
+            apply_catcode_assignment(&mut stream, &mut tokenizer),
+            Err(TextileError::Expansion(ExpansionError::InvalidCategoryCode))
+        );
+
+        for code in &["\"D800", "\"110000", "-1"] {
+            let mut stream = tokens(&format!("{}=11", code)).into_iter().peekable();
+            assert_eq!(
+                apply_catcode_assignment(&mut stream, &mut tokenizer),
+                Err(TextileError::Expansion(ExpansionError::InvalidCharCode))
+            );
+        }
+    }
+
+    #[test]
+    fn catcode_changes() {
+        assert_eq!(
+            extract_catcode_changes(&tokens("\\catcode`\\~=13")),
+            vec![('~', Cat13)]
+        );
+        assert_eq!(
+            extract_catcode_changes(&tokens(
+                "a\\catcode64 11 \\catcode`\\~=16 \\relax\\catcode'44=12"
+            )),
+            vec![('@', Cat11), ('$', Cat12)]
+        );
+        assert_eq!(extract_catcode_changes(&tokens("\\catcode x")), vec![]);
+    }
+}
@@ -1,12 +1,21 @@
 #[cfg(test)]
 mod tokenizer_test {
+    use crate::error::TextileError;
     use crate::token::{Category::*, OtherToken::*, Token::*, *};
+    use crate::token_list::token_diff;
 
     fn token_vec(input: &str) -> Vec<Token> {
         let tokenizer = Tokenizer::new(input.lines().map(|s| s.to_owned()));
         tokenizer.collect()
     }
 
+    /// Assert that `input` is tokenized to `expected`, reporting the first difference.
+    fn assert_tokens(input: &str, expected: &[Token]) {
+        if let Some(diff) = token_diff(expected, &token_vec(input)) {
+            panic!("tokenizing {:?}: {}", input, diff);
+        }
+    }
+
     #[test]
     fn test_letter() {
         assert_eq!(
@@ -126,8 +135,8 @@ mod tokenizer_test {
             vec![
                 Character('a', Cat11),
                 Character('b', Cat11),
-                Other(Skipped("defgh\r".into()), Span::new(1, 2, 10)),
                 Character(' ', Cat10),
+                Other(Skipped("defgh\r".into()), Span::new(1, 5, 10)),
                 ControlSequence("a".into(), Span::new(2, 0, 1)),
             ]
         );
@@ -191,21 +200,21 @@ mod tokenizer_test {
 
     #[test]
     fn test_hidden_categories() {
-        assert_eq!(
-            token_vec("he\0llo"),
-            vec![
+        assert_tokens(
+            "he\0llo",
+            &[
                 Character('h', Cat11),
                 Character('e', Cat11),
                 Other(IgnoredCharacter('\0'), Span::new(1, 2, 2)),
                 Character('l', Cat11),
                 Character('l', Cat11),
                 Character('o', Cat11),
-                Character(' ', Cat10)
-            ]
+                Character(' ', Cat10),
+            ],
         );
-        assert_eq!(
-            token_vec("he\0llo\x01\x1f"),
-            vec![
+        assert_tokens(
+            "he\0llo\x01\x1f",
+            &[
                 Character('h', Cat11),
                 Character('e', Cat11),
                 Other(IgnoredCharacter('\0'), Span::new(1, 2, 2)),
@@ -214,8 +223,8 @@ mod tokenizer_test {
                 Character('o', Cat11),
                 Other(InvalidCharacter('\x01'), Span::new(1, 6, 6)),
                 Other(InvalidCharacter('\x1f'), Span::new(1, 7, 7)),
-                Character(' ', Cat10)
-            ]
+                Character(' ', Cat10),
+            ],
         );
     }
 
@@ -223,20 +232,16 @@ mod tokenizer_test {
     fn change_catcode() {
         fn tokenize(
             input: &'static str,
-            mapping: &Fn(Box<&mut dyn TokenizerInteraction>, &Token),
+            mapping: &dyn Fn(Box<&mut dyn TokenizerInteraction>, &Token),
         ) -> Vec<Token> {
             let mut result: Vec<Token> = vec![];
             let mut tokenizer = Tokenizer::new(input.lines().map(|s| s.to_owned()));
-            loop {
-                let token = match tokenizer.next() {
-                    Some(t) => t,
-                    None => break,
-                };
+            while let Some(token) = tokenizer.next() {
                 mapping(Box::new(&mut tokenizer), &token);
                 result.push(token);
             }
             result
-        };
+        }
 
         assert_eq!(
             tokenize("a \\a b", &|t, token| {
@@ -272,7 +277,7 @@ mod tokenizer_test {
                 Character(' ', Cat10),
                 Other(
                     OtherToken::Skipped("ello world\r".into()),
-                    Span::new(1, 7, 18)
+                    Span::new(1, 8, 18)
                 ),
                 // no additional space here, as the tokenizer was in SkippingBlanks before
                 // the line ending
@@ -285,4 +290,1450 @@ mod tokenizer_test {
             ]
         );
     }
+
+    #[test]
+    fn recover_remaining_input() {
+        let mut tokenizer =
+            Tokenizer::new(vec!["\\foo bar".to_owned(), "baz".to_owned()].into_iter());
+        assert_eq!(
+            tokenizer.next(),
+            Some(ControlSequence("foo".into(), Span::new(1, 0, 3)))
+        );
+        let (rest, mut lines) = tokenizer.into_remaining();
+        assert_eq!(rest, " bar\r");
+        assert_eq!(lines.next(), Some("baz".to_owned()));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn inject_tokens() {
+        let mut tokenizer = Tokenizer::new("ab^^Mcd\ne".lines().map(|s| s.to_owned()));
+        assert_eq!(tokenizer.next(), Some(Character('a', Cat11)));
+        assert_eq!(tokenizer.next(), Some(Character('b', Cat11)));
+        assert_eq!(tokenizer.next(), Some(Character(' ', Cat10)));
+        // the skipped input is still buffered when the tokens are injected
+        tokenizer.inject_after(vec![
+            ControlSequence("x".into(), Span::any()),
+            Character('%', Cat12),
+        ]);
+        tokenizer.inject_after(vec![Character('y', Cat11), Character('z', Cat11)]);
+        // injected tokens are not affected by catcode changes
+        tokenizer.catcode('%', Cat14);
+        let rest: Vec<Token> = tokenizer.collect();
+        assert_eq!(
+            rest,
+            vec![
+                Other(Skipped("cd\r".into()), Span::new(1, 5, 7)),
+                ControlSequence("x".into(), Span::any()),
+                Character('%', Cat12),
+                Character('y', Cat11),
+                Character('z', Cat11),
+                Character('e', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+    }
+
+    #[test]
+    fn unusual_control_sequences() {
+        let tokens = token_vec("\\ a");
+        assert!(tokens[0].is_control_space());
+        assert!(!tokens[0].is_null_cs());
+
+        let mut tokenizer = Tokenizer::new("a\\".lines().map(|s| s.to_owned()));
+        tokenizer.set_endlinechar(std::char::from_u32(256).unwrap());
+        let tokens: Vec<Token> = tokenizer.collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Character('a', Cat11),
+                ControlSequence("".into(), Span::new(1, 1, 1))
+            ]
+        );
+        assert!(tokens[1].is_null_cs());
+        assert!(!tokens[1].is_control_space());
+        assert!(!tokens[0].is_null_cs());
+    }
+
+    #[test]
+    fn skip_to_control_sequence() {
+        let mut tokenizer = Tokenizer::new("foo \\bar baz".lines().map(|s| s.to_owned()));
+        assert_eq!(tokenizer.skip_until_cs("bar"), Some(Span::new(1, 4, 7)));
+        assert_eq!(
+            tokenizer.next(),
+            Some(Other(Skipped(" ".into()), Span::new(1, 8, 8)))
+        );
+        assert_eq!(tokenizer.next(), Some(Character('b', Cat11)));
+        assert_eq!(tokenizer.skip_until_cs("bar"), None);
+
+        // control sequences in comments are not found
+        let mut tokenizer = Tokenizer::new("% \\bar\na".lines().map(|s| s.to_owned()));
+        assert_eq!(tokenizer.skip_until_cs("bar"), None);
+    }
+
+    #[test]
+    fn skip_groups() {
+        let mut tokenizer = Tokenizer::new("x {a{b}c}d".lines().map(|s| s.to_owned()));
+        assert_eq!(tokenizer.next(), Some(Character('x', Cat11)));
+        assert_eq!(tokenizer.next(), Some(Character(' ', Cat10)));
+        assert_eq!(tokenizer.skip_group(), Ok(Span::new(1, 2, 8)));
+        assert_eq!(tokenizer.next(), Some(Character('d', Cat11)));
+
+        // the group is not the next token
+        let mut tokenizer = Tokenizer::new("\\a{}".lines().map(|s| s.to_owned()));
+        assert_eq!(
+            tokenizer.skip_group(),
+            Err(TextileError::Syntax(Span::new(1, 0, 1)))
+        );
+
+        let mut tokenizer = Tokenizer::new("{a{b}\nc".lines().map(|s| s.to_owned()));
+        assert_eq!(
+            tokenizer.skip_group(),
+            Err(TextileError::Syntax(Span::new(2, 2, 2)))
+        );
+    }
+
+    #[test]
+    fn runaway_hex_escape() {
+        assert_eq!(
+            token_vec("a^^f"),
+            vec![
+                Character('a', Cat11),
+                Character('&', Cat4),
+                Other(Runaway("^^f".into()), Span::new(1, 1, 3)),
+                Character(' ', Cat10)
+            ]
+        );
+        // the diagnostic follows the control sequence built from the escape
+        assert_eq!(
+            token_vec("\\a\\^^f"),
+            vec![
+                ControlSequence("a".into(), Span::new(1, 0, 1)),
+                ControlSequence("&".into(), Span::new(1, 2, 5)),
+                Other(Runaway("^^f".into()), Span::new(1, 3, 5)),
+                Character(' ', Cat10)
+            ]
+        );
+        // comments are not read, so they hold no runaway input
+        assert_eq!(
+            token_vec("a%^^f"),
+            vec![
+                Character('a', Cat11),
+                Other(Comment("&\r".into()), Span::new(1, 1, 5)),
+            ]
+        );
+        // trailing spaces are removed before the line is tokenized
+        assert_eq!(
+            token_vec("^^5   "),
+            vec![
+                Character('u', Cat11),
+                Other(Runaway("^^5".into()), Span::new(1, 0, 2)),
+                Character(' ', Cat10)
+            ]
+        );
+        assert_eq!(
+            token_vec("^^f x"),
+            vec![
+                Character('&', Cat4),
+                Character(' ', Cat10),
+                Character('x', Cat11),
+                Character(' ', Cat10)
+            ]
+        );
+        assert_eq!(
+            token_vec("^^z"),
+            vec![Character(':', Cat12), Character(' ', Cat10)]
+        );
+    }
+
+    #[test]
+    fn everypar() {
+        let mut tokenizer =
+            Tokenizer::new("a\n\n  \n \\par  b c\n\nd".lines().map(|s| s.to_owned()));
+        tokenizer.set_everypar(vec![ControlSequence("marker".into(), Span::any())]);
+        let tokens: Vec<Token> = tokenizer.collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Character('a', Cat11),
+                Character(' ', Cat10),
+                ControlSequence("par".into(), Span::any()),
+                ControlSequence("par".into(), Span::any()),
+                Other(Skipped(" ".into()), Span::new(4, 0, 0)),
+                ControlSequence("par".into(), Span::new(4, 1, 4)),
+                Other(Skipped("  ".into()), Span::new(4, 5, 6)),
+                ControlSequence("marker".into(), Span::any()),
+                Character('b', Cat11),
+                Character(' ', Cat10),
+                Character('c', Cat11),
+                Character(' ', Cat10),
+                ControlSequence("par".into(), Span::any()),
+                ControlSequence("marker".into(), Span::any()),
+                Character('d', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+    }
+
+    #[test]
+    fn multibyte_characters() {
+        assert_eq!(
+            token_vec("éa"),
+            vec![
+                Character('é', Cat12),
+                Character('a', Cat11),
+                Character(' ', Cat10)
+            ]
+        );
+        // columns are byte offsets into the line
+        assert_eq!(
+            token_vec("\\é  \\∀x"),
+            vec![
+                ControlSequence("é".into(), Span::new(1, 0, 2)),
+                Character(' ', Cat10),
+                Other(Skipped(" ".into()), Span::new(1, 4, 4)),
+                ControlSequence("∀".into(), Span::new(1, 5, 8)),
+                Character('x', Cat11),
+                Character(' ', Cat10)
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_catcodes() {
+        let mut tokenizer = Tokenizer::new("a~b~".lines().map(|s| s.to_owned()));
+        assert_eq!(tokenizer.next(), Some(Character('a', Cat11)));
+        tokenizer.catcode('~', Cat11);
+        tokenizer.catcode('b', Cat12);
+        assert_eq!(tokenizer.next(), Some(Character('~', Cat11)));
+        tokenizer.reset_catcodes();
+        let rest: Vec<Token> = tokenizer.collect();
+        assert_eq!(
+            rest,
+            vec![
+                Character('b', Cat11),
+                Character('~', Cat13),
+                Character(' ', Cat10)
+            ]
+        );
+    }
+
+    #[test]
+    fn endline_comment() {
+        let mut tokenizer = Tokenizer::new("ab\nc  \n".lines().map(|s| s.to_owned()));
+        tokenizer.set_endline_comment();
+        let tokens: Vec<Token> = tokenizer.collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Character('a', Cat11),
+                Character('b', Cat11),
+                Character('c', Cat11)
+            ]
+        );
+    }
+
+    #[test]
+    fn join_continuation_lines() {
+        let tokens: Vec<Token> =
+            Tokenizer::new("fo\\  \no\\\nbar\n\\\\\n\\x".lines().map(|s| s.to_owned()))
+                .join_continuations(true)
+                .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Character('f', Cat11),
+                Character('o', Cat11),
+                Character('o', Cat11),
+                Character('b', Cat11),
+                Character('a', Cat11),
+                Character('r', Cat11),
+                Character(' ', Cat10),
+                // a control symbol does not continue the line
+                ControlSequence("\\".into(), Span::new(4, 0, 1)),
+                Character(' ', Cat10),
+                ControlSequence("x".into(), Span::new(5, 0, 1)),
+            ]
+        );
+
+        // without joining, the escape character is followed by the endlinechar
+        assert_eq!(
+            token_vec("a\\\nb")[1],
+            ControlSequence("\r".into(), Span::new(1, 1, 2))
+        );
+    }
+
+    #[test]
+    fn lsp_range() {
+        let line = "😀 \\foo";
+        let tokens = token_vec(line);
+        assert_eq!(tokens[2], ControlSequence("foo".into(), Span::new(1, 5, 8)));
+        assert_eq!(
+            tokens[2].span().unwrap().to_lsp_range(line),
+            ((0, 3), (0, 7))
+        );
+
+        let tokens = token_vec("\n😀😀%x");
+        let comment = tokens[3].span().unwrap();
+        assert_eq!(comment, &Span::new(2, 8, 10));
+        // the endlinechar is not part of the line text
+        assert_eq!(comment.to_lsp_range("😀😀%x"), ((1, 4), (1, 7)));
+    }
+
+    #[test]
+    fn raw_characters() {
+        let mut tokenizer = Tokenizer::new("\\foo".lines().map(|s| s.to_owned())).raw_mode(true);
+        tokenizer.set_endline_comment();
+        let tokens: Vec<Token> = tokenizer.collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Character('\\', Cat0),
+                Character('f', Cat11),
+                Character('o', Cat11),
+                Character('o', Cat11),
+            ]
+        );
+
+        let tokens: Vec<Token> = Tokenizer::new("a  %^^41".lines().map(|s| s.to_owned()))
+            .raw_mode(true)
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Character('a', Cat11),
+                Character(' ', Cat10),
+                Character(' ', Cat10),
+                Character('%', Cat14),
+                Character('A', Cat11),
+                Character('\r', Cat5),
+            ]
+        );
+    }
+
+    /// Pseudo-random ASCII lines, biased towards characters with special categories.
+    fn random_input(seed: u64, length: usize) -> String {
+        const ALPHABET: &[u8] = b"ab \\\\{}%^^^$&#_~12\t\r\n\x00\x7f.M";
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                ALPHABET[(state >> 33) as usize % ALPHABET.len()] as char
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spans_are_monotonic() {
+        for seed in 0..500 {
+            let input = random_input(seed, 60);
+            let tokens = token_vec(&input);
+            let spans: Vec<&Span> = tokens
+                .iter()
+                // runaway escapes annotate characters inside the following token
+                .filter(|t| !matches!(t, Other(Runaway(_), _)))
+                .filter_map(Token::span)
+                .collect();
+            for pair in spans.windows(2) {
+                let (prev, next) = (pair[0], pair[1]);
+                assert!(
+                    prev.line < next.line || prev.line == next.line && prev.end < next.start,
+                    "overlapping spans {:?} and {:?} for input {:?}:\n{}",
+                    prev,
+                    next,
+                    input,
+                    crate::token_list::dump_tokens(&tokens)
+                );
+                assert!(prev.start <= prev.end);
+            }
+        }
+    }
+
+    #[test]
+    fn ignore_spaces() {
+        let mut tokenizer = Tokenizer::new("a   b c".lines().map(|s| s.to_owned()));
+        assert_eq!(tokenizer.next(), Some(Character('a', Cat11)));
+        tokenizer.ignore_following_spaces();
+        let rest: Vec<Token> = tokenizer.collect();
+        assert_eq!(
+            rest,
+            vec![
+                Other(Skipped("   ".into()), Span::new(1, 1, 3)),
+                Character('b', Cat11),
+                Character(' ', Cat10),
+                Character('c', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_separators() {
+        let tokenize = |input: &str| -> Vec<Token> {
+            Tokenizer::new(input.lines().map(|s| s.to_owned()))
+                .unicode_line_separators(true)
+                .collect()
+        };
+        assert_eq!(tokenize("a\u{2028}b"), token_vec("a\nb"));
+        assert_eq!(tokenize("a  \u{2029}\\b"), token_vec("a\n\n\\b"));
+        assert_eq!(
+            tokenize("a\u{2029}b\nc"),
+            vec![
+                Character('a', Cat11),
+                Character(' ', Cat10),
+                ControlSequence("par".into(), Span::new(2, 0, 0)),
+                Character('b', Cat11),
+                Character(' ', Cat10),
+                Character('c', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+
+        // without the option, the separators are just other characters
+        assert_eq!(
+            token_vec("\u{2028}"),
+            vec![Character('\u{2028}', Cat12), Character(' ', Cat10)]
+        );
+    }
+
+    #[test]
+    fn continuations_across_unicode_separators() {
+        let tokenize = |lines: &[&str]| -> Vec<Token> {
+            let lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
+            Tokenizer::new(lines.into_iter())
+                .unicode_line_separators(true)
+                .join_continuations(true)
+                .collect()
+        };
+        // the continuation is the queued part, not the next input line
+        assert_eq!(tokenize(&["a\\\u{2028}b", "c"]), token_vec("ab\nc"));
+        // a fetched continuation is split as well
+        assert_eq!(tokenize(&["a\\", "b\u{2028}c"]), token_vec("ab\nc"));
+    }
+
+    #[test]
+    fn endlinechar_per_line() {
+        let mut tokenizer = Tokenizer::new("a\nb\nc".lines().map(|s| s.to_owned()));
+        assert_eq!(tokenizer.current_endlinechar(), None);
+        assert_eq!(tokenizer.next(), Some(Character('a', Cat11)));
+        assert_eq!(tokenizer.current_endlinechar(), Some('\r'));
+        tokenizer.set_endlinechar('\n');
+        assert_eq!(tokenizer.next(), Some(Character(' ', Cat10)));
+        assert_eq!(tokenizer.current_endlinechar(), Some('\r'));
+        assert_eq!(tokenizer.next(), Some(Character('b', Cat11)));
+        assert_eq!(tokenizer.current_endlinechar(), Some('\n'));
+        tokenizer.set_endline_comment();
+        assert_eq!(tokenizer.next(), Some(Character(' ', Cat10)));
+        assert_eq!(tokenizer.next(), Some(Character('c', Cat11)));
+        assert_eq!(tokenizer.current_endlinechar(), None);
+    }
+
+    #[test]
+    fn ignored_character_line_is_par() {
+        assert_tokens(
+            "a\n\0   \nb",
+            &[
+                Character('a', Cat11),
+                Character(' ', Cat10),
+                Other(OtherToken::IgnoredCharacter('\0'), Span::new(2, 0, 0)),
+                ControlSequence("par".into(), Span::new(2, 1, 1)),
+                Character('b', Cat11),
+                Character(' ', Cat10),
+            ],
+        );
+        assert_tokens(
+            "  \0  ",
+            &[
+                Other(OtherToken::Skipped("  ".into()), Span::new(1, 0, 1)),
+                Other(OtherToken::IgnoredCharacter('\0'), Span::new(1, 2, 2)),
+                ControlSequence("par".into(), Span::new(1, 3, 3)),
+            ],
+        );
+    }
+
+    #[test]
+    fn catcode_range() {
+        let mut tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        assert_eq!(tokenizer.catcode_range('m'), (97..123, Cat11));
+        tokenizer.catcode('m', Cat12);
+        assert_eq!(tokenizer.catcode_range('m'), (109..110, Cat12));
+        assert_eq!(tokenizer.catcode_range('n'), (110..123, Cat11));
+    }
+
+    #[test]
+    fn catcode_scopes() {
+        let mut tokenizer = Tokenizer::new(std::iter::once("@!".to_owned()));
+        tokenizer.push_catcode_scope();
+        tokenizer.catcode('@', Cat11);
+        tokenizer.push_catcode_scope();
+        tokenizer.catcode_global('!', Cat11);
+        tokenizer.catcode('@', Cat13);
+        assert!(tokenizer.pop_catcode_scope());
+        assert!(tokenizer.pop_catcode_scope());
+        assert!(!tokenizer.pop_catcode_scope());
+        assert_eq!(tokenizer.next(), Some(Character('@', Cat12)));
+        assert_eq!(tokenizer.next(), Some(Character('!', Cat11)));
+    }
+
+    #[test]
+    fn tokenize_lines() {
+        let mut tokenizer = Tokenizer::new(std::iter::once("rest".to_owned()));
+        assert_eq!(
+            tokenizer.tokenize_line("\\foo   ".into()),
+            vec![ControlSequence("foo".into(), Span::new(1, 0, 3))]
+        );
+        tokenizer.catcode('@', Cat11);
+        assert_eq!(
+            tokenizer.tokenize_line("  \\a@ b".into()),
+            vec![
+                Other(OtherToken::Skipped("  ".into()), Span::new(2, 0, 1)),
+                ControlSequence("a@".into(), Span::new(2, 2, 4)),
+                Other(OtherToken::Skipped(" ".into()), Span::new(2, 5, 5)),
+                Character('b', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+        assert_eq!(
+            tokenizer.tokenize_line(String::new()),
+            vec![ControlSequence("par".into(), Span::new(3, 0, 0))]
+        );
+        assert_eq!(tokenizer.next(), Some(Character('r', Cat11)));
+    }
+
+    #[test]
+    fn tokenize_lines_across_states() {
+        use std::sync::{Arc, Mutex};
+        use TokenizerState::*;
+
+        // the line ends in state S, so the end of line produces no space
+        let mut tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&states);
+        tokenizer.on_transition(Box::new(move |old, new| {
+            recorder.lock().unwrap().push((old, new))
+        }));
+        assert_eq!(
+            tokenizer.tokenize_line("\\foo".into()),
+            vec![ControlSequence("foo".into(), Span::new(1, 0, 3))]
+        );
+        assert_eq!(
+            *states.lock().unwrap(),
+            vec![(LineStart, SkippingBlanks), (SkippingBlanks, LineStart)]
+        );
+        // the next line starts in state N, skipping its leading spaces
+        assert_eq!(
+            tokenizer.tokenize_line("   x".into()),
+            vec![
+                Other(OtherToken::Skipped("   ".into()), Span::new(2, 0, 2)),
+                Character('x', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+
+        // a line left in state M is finished before the given line is read
+        let mut tokenizer = Tokenizer::new(std::iter::once("a  b".to_owned()));
+        assert_eq!(tokenizer.next(), Some(Character('a', Cat11)));
+        assert_eq!(
+            tokenizer.tokenize_line(" c".into()),
+            vec![
+                Character(' ', Cat10),
+                Other(OtherToken::Skipped(" ".into()), Span::new(1, 2, 2)),
+                Character('b', Cat11),
+                Character(' ', Cat10),
+                Other(OtherToken::Skipped(" ".into()), Span::new(2, 0, 0)),
+                Character('c', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+        assert_eq!(tokenizer.next(), None);
+    }
+
+    #[test]
+    fn zero_width_span() {
+        let span = Span::at(0, 0);
+        assert_eq!(span, Span::new(0, 0, 0));
+        assert!(!span.is_any());
+        assert_ne!(span, Span::new(1, 0, 0));
+        assert_eq!(Span::any(), span);
+        assert_eq!(Span::at(3, 5), Span::new(3, 5, 5));
+        assert!(span.is_zero_width());
+        assert!(!Span::new(0, 0, 0).is_zero_width());
+        assert_eq!(Span::at(1, 2).to_lsp_range("abc"), ((0, 2), (0, 2)));
+    }
+
+    #[test]
+    fn wildcard_span() {
+        assert_ne!(Span::new(0, 0, 0), Span::new(1, 2, 3));
+        assert_ne!(Span::new(1, 2, 3), Span::at(0, 0));
+        assert_eq!(Span::any(), Span::new(1, 2, 3));
+        assert_eq!(Span::new(1, 2, 3), Span::any());
+        assert!(!Span::new(0, 0, 0).is_any());
+    }
+
+    #[test]
+    fn active_characters() {
+        let mut tokenizer = Tokenizer::new(std::iter::once("ab~cd \\x~y".to_owned()));
+        tokenizer.make_active('~');
+        tokenizer.make_active_range('b', 'c');
+        assert_eq!(
+            tokenizer.collect::<Vec<_>>(),
+            vec![
+                Character('a', Cat11),
+                Character('b', Cat13),
+                Character('~', Cat13),
+                Character('c', Cat13),
+                Character('d', Cat11),
+                Character(' ', Cat10),
+                ControlSequence("x".into(), Span::new(1, 6, 7)),
+                Character('~', Cat13),
+                Character('y', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+    }
+
+    #[test]
+    fn spaces_after_control_symbols() {
+        for symbol in &['+', '.', '1'] {
+            assert_tokens(
+                &format!("\\{}  x", symbol),
+                &[
+                    ControlSequence(symbol.to_string(), Span::new(1, 0, 1)),
+                    Character(' ', Cat10),
+                    Other(OtherToken::Skipped(" ".into()), Span::new(1, 3, 3)),
+                    Character('x', Cat11),
+                    Character(' ', Cat10),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn original_bytes() {
+        let input = "ab   \n\\cd  %x   \n\\x\\\nyz";
+        let mut tokenizer = Tokenizer::new(input.lines().map(|s| s.to_owned()))
+            .join_continuations(true)
+            .track_original_bytes(true);
+        let spans: Vec<Span> = tokenizer
+            .by_ref()
+            .filter_map(|t| t.span().cloned())
+            .collect();
+        let ranges: Vec<_> = spans
+            .iter()
+            .map(|s| tokenizer.original_bytes(s).unwrap())
+            .collect();
+        assert_eq!(ranges, vec![6..9, 9..11, 11..16, 17..23]);
+        assert_eq!(&input[17..23], "\\x\\\nyz");
+        assert_eq!(tokenizer.original_bytes(&Span::new(1, 1, 1)), Some(1..2));
+        // the endlinechar of the first line, after the stripped spaces
+        assert_eq!(tokenizer.original_bytes(&Span::new(1, 2, 2)), Some(5..5));
+        assert_eq!(tokenizer.original_bytes(&Span::new(4, 0, 0)), None);
+        assert_eq!(tokenizer.original_bytes(&Span::any()), None);
+    }
+
+    #[test]
+    fn default_categories() {
+        let tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        for c in &[
+            '\\', '{', '}', '$', '&', '\r', '#', '^', '_', '\0', ' ', 'a', 'Z', '%', '\x7f', '~',
+            '1', 'ä',
+        ] {
+            assert_eq!(default_category(*c), tokenizer.get_catcode(*c), "{:?}", c);
+        }
+        assert_eq!(default_category('q'), Cat11);
+        assert_eq!(default_category('%'), Cat14);
+    }
+
+    #[test]
+    fn fused() {
+        // yields a line again after reporting the end once
+        let mut calls = 0;
+        let lines = std::iter::from_fn(|| {
+            calls += 1;
+            if calls == 2 {
+                None
+            } else {
+                Some("a".to_owned())
+            }
+        });
+        let mut tokenizer = Tokenizer::new(lines);
+        assert_eq!(tokenizer.next(), Some(Character('a', Cat11)));
+        assert_eq!(tokenizer.next(), Some(Character(' ', Cat10)));
+        for _ in 0..5 {
+            assert_eq!(tokenizer.next(), None);
+        }
+        tokenizer.inject_after(vec![Character('b', Cat11)]);
+        assert_eq!(tokenizer.next(), None);
+        assert_eq!(
+            tokenizer.tokenize_line("c".into()),
+            vec![
+                Character('b', Cat11),
+                Character('c', Cat11),
+                Character(' ', Cat10)
+            ]
+        );
+        assert_eq!(tokenizer.next(), None);
+    }
+
+    #[test]
+    fn line_ending_conventions() {
+        let expected = token_vec("a\n\\b c\n\nd");
+        for input in &[
+            "a\r\n\\b c\r\n\r\nd\r\n",
+            "a\r\\b c\r\rd",
+            "a\n\\b c\n\nd\n",
+        ] {
+            let tokens: Vec<Token> = Tokenizer::from_str_crlf(input).collect();
+            assert_eq!(tokens, expected, "{:?}", input);
+        }
+        assert_eq!(Tokenizer::from_str_crlf("").next(), None);
+    }
+
+    #[test]
+    fn tokenizer_stats() {
+        let mut tokenizer = Tokenizer::new("\\a  b% c\n\n{x}".lines().map(|s| s.to_owned()));
+        tokenizer.inject_after(vec![Character('i', Cat11)]);
+        assert_eq!(tokenizer.by_ref().count(), 10);
+        let stats = tokenizer.stats();
+        assert_eq!(stats.control_sequences, 2);
+        assert_eq!(stats.characters(Cat11), 2);
+        assert_eq!(stats.characters(Cat1), 1);
+        assert_eq!(stats.characters(Cat2), 1);
+        assert_eq!(stats.characters(Cat10), 1);
+        assert_eq!(stats.characters(Cat12), 0);
+        assert_eq!(stats.comments, 1);
+        assert_eq!(stats.skipped, 1);
+    }
+
+    #[test]
+    fn filtered_tokens() {
+        let tokenizer = Tokenizer::new("a% one\n\\b % two".lines().map(|s| s.to_owned()));
+        let tokens: Vec<Token> = tokenizer
+            .filtered(|t| !matches!(t, Other(Comment(_), _)))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Character('a', Cat11),
+                ControlSequence("b".into(), Span::new(2, 0, 1)),
+                Other(Skipped(" ".into()), Span::new(2, 2, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn active_char_lookup() {
+        let mut meanings = std::collections::HashMap::new();
+        meanings.insert("~".to_owned(), "nobreakspace");
+        let tilde = token_vec("~").remove(0);
+        assert_eq!(tilde, Character('~', Cat13));
+        let name = match tilde {
+            Character(c, Cat13) => active_char_name(c),
+            _ => unreachable!(),
+        };
+        assert_eq!(name, ControlSequence("~".into(), Span::any()));
+        if let ControlSequence(key, _) = name {
+            assert_eq!(meanings.get(&key), Some(&"nobreakspace"));
+        }
+    }
+
+    #[test]
+    fn reconstruct_with_escapechar() {
+        let mut tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        let tokens = token_vec("\\foo bar\\,\\x y{\\z}");
+        assert_eq!(tokenizer.get_escapechar(), Some('\\'));
+        assert_eq!(tokenizer.to_source(&tokens), "\\foo bar\\,\\x y{\\z} ");
+        tokenizer.set_escapechar('@');
+        assert_eq!(tokenizer.to_source(&tokens), "@foo bar@,@x y{@z} ");
+        assert_eq!(
+            tokenizer.to_source(&[Parameter(1), ControlSequence("a".into(), Span::any())]),
+            "#1@a"
+        );
+    }
+
+    #[test]
+    fn retokenize_line() {
+        let mut tokenizer =
+            Tokenizer::new("a\n\\b c\nd".lines().map(|s| s.to_owned())).track_token_metadata(true);
+        let cached: Vec<Token> = tokenizer.by_ref().collect();
+        let edited = tokenizer
+            .retokenize_line(2, "\\x%".into(), &cached)
+            .unwrap();
+        assert_eq!(edited, token_vec("a\n\\x%\nd"));
+        assert_eq!(edited[..2], cached[..2]);
+        assert_eq!(edited[edited.len() - 2..], cached[cached.len() - 2..]);
+        assert_eq!(edited[2], ControlSequence("x".into(), Span::new(2, 0, 1)));
+
+        // line starts were updated for the edited tokens
+        let edited = tokenizer.retokenize_line(3, "e f".into(), &edited).unwrap();
+        assert_eq!(edited, token_vec("a\n\\x%\ne f"));
+
+        // lines which were not read cannot be replaced
+        assert_eq!(tokenizer.retokenize_line(0, "x".into(), &edited), None);
+        assert_eq!(tokenizer.retokenize_line(4, "x".into(), &edited), None);
+        assert_eq!(tokenizer.retokenize_line(3, "x".into(), &edited[..3]), None);
+
+        // without metadata, the tokens of the lines are not known
+        let mut tokenizer = Tokenizer::new("a\nb".lines().map(|s| s.to_owned()));
+        let cached: Vec<Token> = tokenizer.by_ref().collect();
+        assert_eq!(tokenizer.retokenize_line(1, "x".into(), &cached), None);
+    }
+
+    #[test]
+    fn retokenized_line_metadata() {
+        let mut tokenizer =
+            Tokenizer::new("a%\nb  ".lines().map(|s| s.to_owned())).track_token_metadata(true);
+        let cached: Vec<Token> = tokenizer.by_ref().collect();
+        tokenizer.set_endlinechar('|');
+        let edited = tokenizer.retokenize_line(1, "x%".into(), &cached).unwrap();
+        assert_eq!(
+            tokenizer.line_metadata().unwrap()[0],
+            LineMeta {
+                number: 1,
+                endlinechar: Some('|'),
+                trailing_stripped: 0,
+            }
+        );
+        assert_eq!(tokenizer.line_metadata().unwrap()[1].trailing_stripped, 2);
+        assert_eq!(tokenizer.to_source(&edited[..2]), "x%\n");
+    }
+
+    #[test]
+    fn list_escape_chars() {
+        let mut tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        assert_eq!(tokenizer.escape_chars(), vec!['\\']);
+        tokenizer.catcode('@', Cat0);
+        assert_eq!(tokenizer.escape_chars(), vec!['@', '\\']);
+        tokenizer.catcode('\\', Cat12);
+        assert_eq!(tokenizer.escape_chars(), vec!['@']);
+    }
+
+    #[test]
+    fn superscript_non_ascii_digit() {
+        // '²' is numeric, but not a hex digit, so only the '1' is escaped
+        assert_tokens(
+            "^^1²",
+            &[
+                Character('q', Cat11),
+                Character('²', Cat12),
+                Character(' ', Cat10),
+            ],
+        );
+        assert_tokens(
+            "\\^^1²",
+            &[
+                ControlSequence("q".into(), Span::new(1, 0, 3)),
+                Character('²', Cat12),
+                Character(' ', Cat10),
+            ],
+        );
+    }
+
+    #[test]
+    fn recursive_superscripts() {
+        assert_tokens("^^5e^41", &[Character('A', Cat11), Character(' ', Cat10)]);
+        // the decoded ^ and the next two ^ form an escape of ^
+        assert_tokens(
+            "^^5e^^41",
+            &[
+                Other(InvalidCharacter('\x1e'), Span::new(1, 0, 5)),
+                Character('4', Cat12),
+                Character('1', Cat12),
+                Character(' ', Cat10),
+            ],
+        );
+        assert_tokens(
+            "\\^^5e^5e^41",
+            &[ControlSequence("A".into(), Span::new(1, 0, 10))],
+        );
+    }
+
+    #[test]
+    fn read_arguments() {
+        let mut tokenizer = Tokenizer::new("{ab} a\\x{a{b}%\n}".lines().map(|s| s.to_owned()));
+        assert_eq!(
+            tokenizer.read_argument(),
+            Ok(vec![Character('a', Cat11), Character('b', Cat11)])
+        );
+        assert_eq!(tokenizer.read_argument(), Ok(vec![Character('a', Cat11)]));
+        assert_eq!(
+            tokenizer.read_argument(),
+            Ok(vec![ControlSequence("x".into(), Span::new(1, 6, 7))])
+        );
+        assert_eq!(
+            tokenizer.read_argument(),
+            Ok(vec![
+                Character('a', Cat11),
+                Character('{', Cat1),
+                Character('b', Cat11),
+                Character('}', Cat2),
+            ])
+        );
+        assert_eq!(
+            tokenizer.read_argument(),
+            Err(TextileError::Syntax(Span::new(2, 2, 2)))
+        );
+
+        let mut tokenizer = Tokenizer::new(std::iter::once("{a{b}".to_owned()));
+        assert_eq!(
+            tokenizer.read_argument(),
+            Err(TextileError::Syntax(Span::new(1, 6, 6)))
+        );
+        let mut tokenizer = Tokenizer::new(std::iter::once(" }".to_owned()));
+        assert_eq!(
+            tokenizer.read_argument(),
+            Err(TextileError::Syntax(Span::new(1, 1, 1)))
+        );
+    }
+
+    #[test]
+    fn spans_of_inserted_tokens() {
+        // an injected brace is not located in the input
+        let mut tokenizer = Tokenizer::new(std::iter::once("a".to_owned()));
+        tokenizer.inject_after(vec![Character('}', Cat2)]);
+        match tokenizer.read_argument() {
+            Err(TextileError::Syntax(span)) => assert!(span.is_any()),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // neither is a brace of \everypar
+        let lines = vec![String::new(), "x".to_owned()];
+        let mut tokenizer = Tokenizer::new(lines.into_iter());
+        tokenizer.set_everypar(vec![Character('}', Cat2)]);
+        assert_eq!(
+            tokenizer.next(),
+            Some(ControlSequence("par".into(), Span::new(1, 0, 0)))
+        );
+        match tokenizer.read_argument() {
+            Err(TextileError::Syntax(span)) => assert!(span.is_any()),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // the token delayed by \everypar keeps its span
+        let lines = vec![String::new(), "{a}".to_owned()];
+        let mut tokenizer = Tokenizer::new(lines.into_iter());
+        tokenizer.set_everypar(vec![Other(Comment("x".into()), Span::any())]);
+        tokenizer.next();
+        assert_eq!(tokenizer.skip_group(), Ok(Span::new(2, 0, 2)));
+    }
+
+    #[test]
+    fn read_argument_recovery() {
+        let mut tokenizer = Tokenizer::new(std::iter::once("{a{b".to_owned()));
+        let (argument, spans) = tokenizer.read_argument_recovering().unwrap();
+        assert_eq!(
+            argument,
+            vec![
+                Character('a', Cat11),
+                Character('{', Cat1),
+                Character('b', Cat11),
+                Character(' ', Cat10),
+                Character('}', Cat2),
+            ]
+        );
+        // the first span belongs to the synthesized brace of the inner group,
+        // the last one to the stripped brace of the argument
+        assert_eq!(spans.len(), 2);
+        let inner = &spans[0];
+        let outer = &spans[1];
+        assert_eq!(inner, &Span::at(1, 5));
+        assert_eq!(outer, &Span::at(1, 5));
+        assert!(inner.is_zero_width() && outer.is_zero_width());
+        let mut tokenizer = Tokenizer::new(std::iter::once("{{{c".to_owned()));
+        let (argument, spans) = tokenizer.read_argument_recovering().unwrap();
+        let braces = argument
+            .iter()
+            .filter(|t| **t == Character('}', Cat2))
+            .count();
+        assert_eq!((braces, spans.len()), (2, 3));
+
+        let mut tokenizer = Tokenizer::new(std::iter::once("{a}x".to_owned()));
+        assert_eq!(
+            tokenizer.read_argument_recovering(),
+            Ok((vec![Character('a', Cat11)], vec![]))
+        );
+        let mut tokenizer = Tokenizer::new(std::iter::once("}".to_owned()));
+        assert_eq!(
+            tokenizer.read_argument_recovering(),
+            Err(TextileError::Syntax(Span::new(1, 0, 0)))
+        );
+    }
+
+    #[test]
+    fn reconstruct_without_escapechar() {
+        let mut tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        tokenizer.set_escapechar_opt(None);
+        assert_eq!(tokenizer.get_escapechar(), None);
+        let tokens = token_vec("\\foo bar\\,");
+        assert_eq!(tokenizer.to_source(&tokens), "foo bar, ");
+        tokenizer.set_escapechar('\\');
+        assert_eq!(tokenizer.to_source(&tokens), "\\foo bar\\, ");
+    }
+
+    #[test]
+    fn source_round_trip() {
+        let tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        let source = "\\foo  bar% comment\n  \\baz  \\x%\n\\y\\0 \\1 x%\n";
+        assert_eq!(tokenizer.to_source(&token_vec(source)), source);
+    }
+
+    #[test]
+    fn source_round_trip_with_changed_endlinechar() {
+        let mut tokenizer =
+            Tokenizer::new("a%x\nb%y\nc%".lines().map(|s| s.to_owned())).track_token_metadata(true);
+        let mut tokens = vec![tokenizer.next().unwrap()];
+        tokenizer.set_endlinechar('|');
+        tokens.extend(tokenizer.by_ref().take(3));
+        tokenizer.set_endline_comment();
+        tokens.extend(tokenizer.by_ref());
+        assert_eq!(tokens[1], Other(Comment("x\r".into()), Span::new(1, 1, 3)));
+        assert_eq!(tokenizer.to_source(&tokens), "a%x\nb%y\nc%\n");
+    }
+
+    #[test]
+    fn empty_input() {
+        let mut tokenizer: Tokenizer<_> = "".parse().unwrap();
+        assert_eq!(tokenizer.next(), None);
+        assert_eq!(token_vec(""), vec![]);
+        let tokenizer: Tokenizer<_> = "\n".parse().unwrap();
+        assert_eq!(
+            tokenizer.collect::<Vec<_>>(),
+            vec![ControlSequence("par".into(), Span::new(1, 0, 0))]
+        );
+    }
+
+    #[test]
+    fn validate_category_map() {
+        let mut tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        assert_eq!(tokenizer.debug_validate(), Ok(()));
+        tokenizer.catcode('@', Cat11);
+        tokenizer.make_active_range('a', 'z');
+        tokenizer.catcode('@', Cat12);
+        assert_eq!(tokenizer.debug_validate(), Ok(()));
+    }
+
+    #[test]
+    fn borrowed_lines() {
+        let lines: &[&str] = &["\\foo  bar%", "", "  x\\", "y ^^41"];
+        let borrowed: Vec<Token> = Tokenizer::new_borrowed(lines.iter().copied()).collect();
+        let owned: Vec<Token> = Tokenizer::new(lines.iter().map(|l| l.to_string())).collect();
+        assert_eq!(borrowed, owned);
+        assert_eq!(borrowed, token_vec("\\foo  bar%\n\n  x\\\ny ^^41"));
+
+        let joined: Vec<Token> = Tokenizer::new_borrowed(lines.iter().copied())
+            .join_continuations(true)
+            .collect();
+        assert_eq!(joined, token_vec("\\foo  bar%\n\n  xy ^^41"));
+    }
+
+    #[test]
+    fn token_from_char() {
+        assert_eq!(Token::from('a'), Character('a', Cat11));
+        assert_eq!(Token::from(' '), Character(' ', Cat10));
+        let escape: Token = '\\'.into();
+        assert_eq!(escape, Character('\\', Cat0));
+    }
+
+    #[test]
+    fn state_transitions() {
+        use std::sync::{Arc, Mutex};
+        use TokenizerState::*;
+
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&transitions);
+        let mut tokenizer = Tokenizer::new(vec!["a b".to_owned()].into_iter());
+        tokenizer.on_transition(Box::new(move |old, new| {
+            recorder.lock().unwrap().push((old, new))
+        }));
+        // the tokenizer can still be moved to another thread
+        std::thread::spawn(move || assert_eq!(tokenizer.count(), 4))
+            .join()
+            .unwrap();
+        assert_eq!(
+            *transitions.lock().unwrap(),
+            vec![
+                (LineStart, LineMiddle),
+                (LineMiddle, SkippingBlanks),
+                (SkippingBlanks, LineMiddle),
+                (LineMiddle, LineStart),
+            ]
+        );
+    }
+
+    #[test]
+    fn line_metadata() {
+        let lines = vec!["a  ".to_owned(), "b".to_owned()];
+        let mut tokenizer = Tokenizer::new(lines.into_iter()).track_token_metadata(true);
+        assert_eq!(tokenizer.next(), Some(Character('a', Cat11)));
+        tokenizer.set_endlinechar('|');
+        let rest: Vec<Token> = tokenizer.by_ref().collect();
+        assert_eq!(rest.last(), Some(&Character('|', Cat12)));
+        assert_eq!(
+            tokenizer.line_metadata().unwrap(),
+            &[
+                LineMeta {
+                    number: 1,
+                    endlinechar: Some('\r'),
+                    trailing_stripped: 2,
+                },
+                LineMeta {
+                    number: 2,
+                    endlinechar: Some('|'),
+                    trailing_stripped: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn token_metadata_is_opt_in() {
+        let mut tokenizer = Tokenizer::new("\\foo bar\nx".lines().map(|s| s.to_owned()));
+        let tokens: Vec<Token> = tokenizer.by_ref().collect();
+        assert_eq!(tokenizer.line_metadata(), None);
+        assert_eq!(tokenizer.skipped_blanks_after(0), None);
+        assert_eq!(tokenizer.emitted_to_source(0, &tokens), None);
+        assert_eq!(tokenizer.to_source(&tokens), "\\foo bar x ");
+    }
+
+    #[test]
+    fn interleaved_catcode_changes() {
+        let lines = vec!["@a@".to_owned(), "@b@".to_owned()];
+        let mut tokenizer = Tokenizer::new(lines.into_iter());
+        let mut tokens = vec![];
+        while let Some(token) = tokenizer.next() {
+            // @ becomes a letter at the end of the first line
+            let cat = if token == Character(' ', Cat10) {
+                Cat11
+            } else {
+                Cat12
+            };
+            tokens.push(token);
+            tokenizer.catcode('@', cat);
+            tokenizer.catcode('a', Cat11);
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                Character('@', Cat12),
+                Character('a', Cat11),
+                Character('@', Cat12),
+                Character(' ', Cat10),
+                Character('@', Cat11),
+                Character('b', Cat11),
+                Character('@', Cat12),
+                Character(' ', Cat10),
+            ]
+        );
+    }
+
+    #[test]
+    fn limited_token_count() {
+        let input = "\\foo{bar}% baz\n\n x";
+        assert_eq!(token_vec(input).len(), 11);
+        let tokenizer = Tokenizer::new(input.lines().map(|s| s.to_owned()));
+        let limited: Vec<Token> = tokenizer.take_limited(5).collect();
+        assert_eq!(limited, token_vec(input)[..5].to_vec());
+        let tokenizer = Tokenizer::new(input.lines().map(|s| s.to_owned()));
+        assert_eq!(tokenizer.take_limited(20).count(), 11);
+    }
+
+    #[test]
+    fn display_other_tokens() {
+        assert_eq!(Comment(" note".into()).to_string(), "% note");
+        assert_eq!(Skipped("  ".into()).to_string(), "  ");
+        assert_eq!(IgnoredCharacter('\0').to_string(), "\0");
+        assert_eq!(InvalidCharacter('\u{7f}').to_string(), "\u{7f}");
+        assert_eq!(Runaway("^^4".into()).to_string(), "");
+    }
+
+    #[test]
+    fn crlf_lines() {
+        // lines split at \n only, so the \r of CRLF line endings remains
+        let crlf: Vec<Token> = Tokenizer::new(
+            vec!["a\r", "\r", "b  %c\r", "\\x\r"]
+                .into_iter()
+                .map(|s| s.to_owned()),
+        )
+        .collect();
+        assert_eq!(crlf, token_vec("a\n\nb  %c\n\\x"));
+        assert_eq!(
+            crlf,
+            vec![
+                Character('a', Cat11),
+                Character(' ', Cat10),
+                ControlSequence("par".into(), Span::new(2, 0, 0)),
+                Character('b', Cat11),
+                Character(' ', Cat10),
+                Other(Skipped(" ".into()), Span::new(3, 2, 2)),
+                Other(Comment("c\r".into()), Span::new(3, 3, 5)),
+                ControlSequence("x".into(), Span::new(4, 0, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn classify_text() {
+        let mut tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        assert_eq!(
+            tokenizer.classify("a{b"),
+            vec![('a', Cat11), ('{', Cat1), ('b', Cat11)]
+        );
+        assert_eq!(tokenizer.classify("^^41"), vec![('A', Cat11)]);
+        assert_eq!(
+            tokenizer.classify("\\^^5c% "),
+            vec![('\\', Cat0), ('\\', Cat0), ('%', Cat14), (' ', Cat10)]
+        );
+        tokenizer.catcode('a', Cat12);
+        assert_eq!(tokenizer.classify("a"), vec![('a', Cat12)]);
+        assert_eq!(tokenizer.classify(""), vec![]);
+    }
+
+    #[test]
+    fn source_without_end_of_line_spaces() {
+        let sources = [
+            ("\\foo", "\\foo"),
+            ("\\foo x", "\\foo x\n"),
+            ("a\nb\n\n\\x y", "a\nb\n\\par\\x y\n"),
+        ];
+        for (source, expected) in &sources {
+            let mut tokenizer =
+                Tokenizer::new(source.lines().map(|s| s.to_owned())).track_token_metadata(true);
+            let tokens: Vec<Token> = tokenizer.by_ref().collect();
+            assert_eq!(tokenizer.emitted_to_source(0, &tokens).unwrap(), *expected);
+        }
+        // parts of the emitted tokens are located by their first index
+        let mut tokenizer = Tokenizer::new("a\nb\n\n\\x y".lines().map(|s| s.to_owned()))
+            .track_token_metadata(true);
+        let tokens: Vec<Token> = tokenizer.by_ref().collect();
+        assert_eq!(
+            tokenizer.emitted_to_source(1, &tokens[1..]).unwrap(),
+            "\nb\n\\par\\x y\n"
+        );
+        assert_eq!(tokenizer.emitted_to_source(3, &tokens[3..4]).unwrap(), "\n");
+        // spaces of other token lists are kept
+        assert_eq!(tokenizer.to_source(&tokens[1..]), " b \\par\\x y ");
+        assert_eq!(tokenizer.to_source(&token_vec("\\foo x")), "\\foo x ");
+    }
+
+    #[test]
+    fn retokenized_end_of_line_spaces() {
+        let mut tokenizer =
+            Tokenizer::new("a\nb%\nc".lines().map(|s| s.to_owned())).track_token_metadata(true);
+        let tokens: Vec<Token> = tokenizer.by_ref().collect();
+        let tokens = tokenizer
+            .retokenize_line(2, "x y".to_owned(), &tokens)
+            .unwrap();
+        assert_eq!(
+            tokenizer.emitted_to_source(0, &tokens).unwrap(),
+            "a\nx y\nc\n"
+        );
+    }
+
+    #[test]
+    fn catcode_by_number() {
+        let mut tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        assert_eq!(tokenizer.get_catcode_u32(92), Cat0);
+        assert_eq!(tokenizer.get_catcode_u32('a' as u32), Cat11);
+        tokenizer.catcode('a', Cat13);
+        assert_eq!(tokenizer.get_catcode_u32('a' as u32), Cat13);
+        assert_eq!(tokenizer.get_catcode_u32(0xD800), Cat12);
+        assert_eq!(tokenizer.get_catcode_u32(0x110000), Cat12);
+    }
+
+    #[test]
+    fn blanks_after_control_sequences() {
+        let blanks = |input: &str| {
+            let mut tokenizer =
+                Tokenizer::new(input.lines().map(|s| s.to_owned())).track_token_metadata(true);
+            let tokens: Vec<Token> = tokenizer.by_ref().collect();
+            (0..tokens.len())
+                .filter(|i| tokenizer.skipped_blanks_after(*i).unwrap())
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(blanks("\\foo bar"), vec![0]);
+        assert_eq!(blanks("\\foo{x}"), vec![]);
+        // the end of line is a blank, but the control symbol does not skip blanks
+        assert_eq!(blanks("\\foo\n\\, x\\  \\y"), vec![0, 4, 6]);
+    }
+
+    #[test]
+    fn scheduled_catcodes() {
+        let mut tokenizer = Tokenizer::new(vec!["a@b@".to_owned(), "@".to_owned()].into_iter());
+        tokenizer.schedule_catcode_at(2, '@', Cat11);
+        tokenizer.schedule_catcode_at(9, '@', Cat13);
+        assert_eq!(
+            tokenizer.collect::<Vec<Token>>(),
+            vec![
+                Character('a', Cat11),
+                Character('@', Cat12),
+                Character('b', Cat11),
+                Character('@', Cat11),
+                Character(' ', Cat10),
+                Character('@', Cat13),
+                Character(' ', Cat10),
+            ]
+        );
+
+        // the control word ends where the category changes
+        let mut tokenizer = Tokenizer::new(std::iter::once("\\ab@c".to_owned()));
+        tokenizer.schedule_catcode_at(2, 'b', Cat12);
+        assert_eq!(
+            tokenizer.collect::<Vec<Token>>(),
+            vec![
+                ControlSequence("a".into(), Span::new(1, 0, 1)),
+                Character('b', Cat12),
+                Character('@', Cat12),
+                Character('c', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+    }
+
+    #[test]
+    fn verbatim_arguments() {
+        let mut tokenizer = Tokenizer::new(std::iter::once("\\verb+co%de+ x".to_owned()));
+        assert_eq!(
+            tokenizer.next(),
+            Some(ControlSequence("verb".into(), Span::new(1, 0, 4)))
+        );
+        assert_eq!(
+            tokenizer.read_verb(),
+            Ok(('+', "co%de".to_owned(), Span::new(1, 5, 11)))
+        );
+        assert_eq!(
+            tokenizer.collect::<Vec<Token>>(),
+            vec![
+                Character(' ', Cat10),
+                Character('x', Cat11),
+                Character(' ', Cat10)
+            ]
+        );
+
+        let mut tokenizer = Tokenizer::new(std::iter::once("\\verb  |a+b|\\x".to_owned()));
+        tokenizer.next();
+        assert_eq!(
+            tokenizer.read_verb(),
+            Ok(('|', "a+b".to_owned(), Span::new(1, 7, 11)))
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Some(ControlSequence("x".into(), Span::new(1, 12, 13)))
+        );
+
+        // letters can be delimiters, too
+        let mut tokenizer = Tokenizer::new(std::iter::once("\\verb x\\y^^41x".to_owned()));
+        tokenizer.next();
+        assert_eq!(
+            tokenizer.read_verb(),
+            Ok(('x', "\\y^^41".to_owned(), Span::new(1, 6, 13)))
+        );
+
+        let mut tokenizer =
+            Tokenizer::new(vec!["\\verb+ab".to_owned(), "+".to_owned()].into_iter());
+        tokenizer.next();
+        assert_eq!(
+            tokenizer.read_verb(),
+            Err(TextileError::Syntax(Span::new(1, 8, 8)))
+        );
+        assert_eq!(tokenizer.next(), Some(Character('+', Cat12)));
+        let mut tokenizer = Tokenizer::new(std::iter::once("\\verb  ".to_owned()));
+        tokenizer.next();
+        assert_eq!(
+            tokenizer.read_verb(),
+            Err(TextileError::Syntax(Span::new(1, 5, 5)))
+        );
+
+        // a peeked delimiter was already read and cannot be read verbatim
+        let mut tokenizer = Tokenizer::new(std::iter::once("\\verb+a+".to_owned()));
+        tokenizer.next();
+        let peeked = tokenizer.next().unwrap();
+        tokenizer.inject_after(vec![peeked]);
+        assert_eq!(
+            tokenizer.read_verb(),
+            Err(TextileError::Syntax(Span::any()))
+        );
+        assert_eq!(tokenizer.next(), Some(Character('+', Cat12)));
+        assert_eq!(tokenizer.next(), Some(Character('a', Cat11)));
+    }
+
+    #[test]
+    fn reused_line_buffers() {
+        // lines of changing length are copied into the reused buffers
+        let text: String = (0..200)
+            .map(|i| format!("{}\\x{} %{}\n", " ".repeat(i % 7), "y".repeat(i % 13), i))
+            .collect();
+        let owned: Vec<Token> = Tokenizer::new(text.lines().map(|l| l.to_owned())).collect();
+        let borrowed: Vec<Token> = Tokenizer::new_borrowed(text.lines()).collect();
+        assert_eq!(owned, borrowed);
+        assert_eq!(
+            owned,
+            Tokenizer::from_str_crlf(&text).collect::<Vec<Token>>()
+        );
+        let comments = owned
+            .iter()
+            .filter(|t| matches!(t, Other(Comment(_), _)))
+            .count();
+        assert_eq!(comments, 200);
+    }
+
+    #[test]
+    fn input_line_numbers() {
+        let input = "\\a b\n\n  \\c%\n\\d\\\ne";
+        let mut tokenizer = Tokenizer::new(input.lines().map(|s| s.to_owned()));
+        assert_eq!(tokenizer.input_line_no(), 0);
+        while let Some(token) = tokenizer.next() {
+            if let Some(span) = token.span() {
+                assert_eq!(tokenizer.input_line_no(), span.line);
+            }
+        }
+        assert_eq!(tokenizer.input_line_no(), 5);
+
+        let mut tokenizer =
+            Tokenizer::new(input.lines().map(|s| s.to_owned())).join_continuations(true);
+        let last = tokenizer.by_ref().filter_map(|t| t.span().cloned()).last();
+        assert_eq!(last.map(|s| s.line), Some(4));
+        assert_eq!(tokenizer.input_line_no(), 4);
+    }
+
+    #[test]
+    fn literal_tab_characters() {
+        let lines = || vec!["\ta \t\t  ".to_owned(), "\t".to_owned()].into_iter();
+        assert_eq!(
+            Tokenizer::new(lines())
+                .literal_tabs(true)
+                .collect::<Vec<Token>>(),
+            vec![
+                Character('\t', Cat12),
+                Character('a', Cat11),
+                Character(' ', Cat10),
+                Character('\t', Cat12),
+                Character('\t', Cat12),
+                Character(' ', Cat10),
+                Character('\t', Cat12),
+                Character(' ', Cat10),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::new(lines())
+                .literal_tabs(true)
+                .literal_tabs(false)
+                .collect::<Vec<Token>>(),
+            Tokenizer::new(lines()).collect::<Vec<Token>>()
+        );
+    }
+
+    #[test]
+    fn catcode_ranges_of_ascii() {
+        let mut tokenizer = Tokenizer::new(std::iter::empty::<String>());
+        let ascii = tokenizer.catcode_ranges(0..128);
+        assert_eq!(ascii.first().map(|r| r.0.start), Some(0));
+        assert_eq!(ascii.last().map(|r| r.0.end), Some(128));
+        assert!(ascii.windows(2).all(|w| w[0].0.end == w[1].0.start));
+        assert!(ascii.contains(&(97..123, Cat11)));
+        tokenizer.catcode('m', Cat12);
+        let letters = tokenizer.catcode_ranges(100..110);
+        assert_eq!(letters, vec![(100..109, Cat11), (109..110, Cat12)]);
+    }
 }
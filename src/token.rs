@@ -1,5 +1,11 @@
+use crate::error::TextileError;
 use crate::interval_map::{IntIntervalMap, IntervalMap};
 use std::char::from_u32;
+use std::convert::Infallible;
+use std::iter::FusedIterator;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::OnceLock;
 
 /// TeX character codes, as defined on p. 37 of the Texbook.
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -40,8 +46,68 @@ pub enum Category {
 
 use Category::*;
 
+impl Category {
+    /// The category with the given category code, if it is in the range 0 to 15.
+    pub fn from_number(code: i32) -> Option<Category> {
+        let category = match code {
+            0 => Cat0,
+            1 => Cat1,
+            2 => Cat2,
+            3 => Cat3,
+            4 => Cat4,
+            5 => Cat5,
+            6 => Cat6,
+            7 => Cat7,
+            8 => Cat8,
+            9 => Cat9,
+            10 => Cat10,
+            11 => Cat11,
+            12 => Cat12,
+            13 => Cat13,
+            14 => Cat14,
+            15 => Cat15,
+            _ => return None,
+        };
+        Some(category)
+    }
+
+    /// Whether this is the category of math shift characters.
+    pub fn is_math_shift(self) -> bool {
+        self == Cat3
+    }
+
+    /// Whether this is the category of alignment tabs.
+    pub fn is_alignment(self) -> bool {
+        self == Cat4
+    }
+
+    /// Whether this is the category of macro parameter characters.
+    pub fn is_parameter(self) -> bool {
+        self == Cat6
+    }
+}
+
 /// Tokens not normally produced by TeX
+///
+/// New kinds of diagnostics may be added in the future,
+/// so matches on this type must contain a wildcard arm:
+///
+/// ```
+/// use tex_parser::token::OtherToken;
+///
+/// fn is_whitespace(token: &OtherToken) -> bool {
+///     match token {
+///         OtherToken::Skipped(s) => s.chars().all(char::is_whitespace),
+///         OtherToken::IgnoredCharacter(_) => true,
+///         _ => false,
+///     }
+/// }
+///
+/// assert!(is_whitespace(&OtherToken::Skipped("  ".into())));
+/// assert!(!is_whitespace(&OtherToken::Comment("x".into())));
+/// ```
 #[derive(Debug, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum OtherToken {
     Comment(String),
     /// A character of class 9
@@ -51,9 +117,35 @@ pub enum OtherToken {
     /// Input which was skipped, e.g. by a premature end of line
     /// or by skipping spaces.
     Skipped(String),
+    /// Input which looks like it was cut off, e.g. a hex-escaped character
+    /// with only one digit before the end of the line.
+    Runaway(String),
+}
+
+/// Renders the input the token was read from.
+///
+/// A comment is rendered with its percent sign, but without the end of line.
+/// Runaway input is rendered as nothing, as it is part of the input of the
+/// preceding token.
+impl std::fmt::Display for OtherToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OtherToken::Comment(text) => write!(f, "%{}", text),
+            OtherToken::Skipped(text) => write!(f, "{}", text),
+            OtherToken::IgnoredCharacter(c) | OtherToken::InvalidCharacter(c) => {
+                write!(f, "{}", c)
+            }
+            OtherToken::Runaway(_) => Ok(()),
+        }
+    }
 }
 
 /// A location in the input file.
+///
+/// Unless catcodes are changed during tokenization, the spans of consecutive tokens
+/// are ordered and do not overlap. The exception are `OtherToken::Runaway` diagnostics,
+/// which point into the input of the token they follow.
+/// Character tokens have no span, including spaces generated at the end of a line.
 #[derive(Debug, Clone)]
 pub struct Span {
     /// Line *number* the current token is generated from
@@ -62,32 +154,101 @@ pub struct Span {
     pub start: usize,
     /// Index of the last column of the span
     pub end: usize,
+    /// Whether this is the dummy span created by `Span::any()`
+    wildcard: bool,
+    /// Whether this is an insertion point created by `Span::at()`
+    zero_width: bool,
 }
 
 impl Span {
     pub fn new(line: usize, start: usize, end: usize) -> Self {
-        Span { line, start, end }
+        Span {
+            line,
+            start,
+            end,
+            wildcard: false,
+            zero_width: false,
+        }
     }
 
     pub fn extend(&mut self, step: usize) {
         self.end += step;
     }
 
+    /// Zero-width span pointing at `column` of `line`, e.g. for synthetic tokens.
+    ///
+    /// The span covers no input, so `start` and `end` are both `column`. Unlike the
+    /// spans of the tokenizer, `end` is not part of the span in this case. Compared to
+    /// other spans, it is still equal to the span of the character at `column`.
+    pub fn at(line: usize, column: usize) -> Self {
+        Span {
+            line,
+            start: column,
+            end: column,
+            wildcard: false,
+            zero_width: true,
+        }
+    }
+
     /// Dummy span, which is equal to any other span.
+    ///
+    /// The span is explicitly marked as a wildcard, so real spans never match
+    /// each other by accident, including `Span::at(0, 0)`.
     pub fn any() -> Self {
         Span {
             line: 0,
             start: 0,
             end: 0,
+            wildcard: true,
+            zero_width: false,
         }
     }
+
+    /// Convert the span to a range as used by the language server protocol.
+    ///
+    /// LSP positions are pairs of a zero-based line number and a zero-based column
+    /// counted in UTF-16 code units, with an exclusive end. As spans use byte offsets,
+    /// the text of the line is needed for the conversion. Offsets behind the end of
+    /// `line_text`, e.g. of the endlinechar, count as one code unit each.
+    pub fn to_lsp_range(&self, line_text: &str) -> ((u32, u32), (u32, u32)) {
+        let line = self.line.saturating_sub(1) as u32;
+        let end = if self.zero_width {
+            self.end
+        } else {
+            self.end + 1
+        };
+        (
+            (line, utf16_column(line_text, self.start)),
+            (line, utf16_column(line_text, end)),
+        )
+    }
+
+    /// Whether this is the dummy span created by `Span::any()`.
+    pub fn is_any(&self) -> bool {
+        self.wildcard
+    }
+
+    /// Whether this is a zero-width span created by `Span::at()`, covering no input.
+    pub fn is_zero_width(&self) -> bool {
+        self.zero_width
+    }
+}
+
+/// Number of UTF-16 code units in `text` before the byte offset `byte`.
+fn utf16_column(text: &str, byte: usize) -> u32 {
+    let units: usize = text
+        .char_indices()
+        .take_while(|(i, _)| *i < byte)
+        .map(|(_, c)| c.len_utf16())
+        .sum();
+    (units + byte.saturating_sub(text.len())) as u32
 }
 
 impl PartialEq for Span {
     fn eq(&self, other: &Self) -> bool {
         self.start == other.start && self.end == other.end && self.line == other.line
-            || self.start == 0 && self.end == 0 && self.line == 0
-            || other.start == 0 && other.end == 0 && other.line == 0
+            || self.is_any()
+            || other.is_any()
     }
 }
 
@@ -109,14 +270,117 @@ pub enum Token {
     Other(OtherToken, Span),
 }
 
+impl Token {
+    /// The location of the token in the input, if known.
+    pub fn span(&self) -> Option<&Span> {
+        match self {
+            Token::ControlSequence(_, span) | Token::Other(_, span) => Some(span),
+            Token::Character(_, _) | Token::Parameter(_) => None,
+        }
+    }
+
+    /// Whether this is the control space `\ `.
+    pub fn is_control_space(&self) -> bool {
+        match self {
+            Token::ControlSequence(name, _) => name == " ",
+            _ => false,
+        }
+    }
+
+    /// Whether this is a control sequence with an empty name.
+    ///
+    /// The tokenizer produces it for an escape character at the very end of a line,
+    /// which only happens if no endlinechar is appended.
+    pub fn is_null_cs(&self) -> bool {
+        match self {
+            Token::ControlSequence(name, _) => name.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Mutable access to the location of the token, if known.
+    pub fn span_mut(&mut self) -> Option<&mut Span> {
+        match self {
+            Token::ControlSequence(_, span) | Token::Other(_, span) => Some(span),
+            Token::Character(_, _) | Token::Parameter(_) => None,
+        }
+    }
+}
+
+/// A character token with the category of plain TeX and no location.
+impl From<char> for Token {
+    fn from(c: char) -> Self {
+        Token::Character(c, default_category(c))
+    }
+}
+
+/// The control sequence under which the meaning of the active character `c` is looked up.
+///
+/// This lets an expansion engine keep active characters in the same table as control
+/// sequences. The key is the single-character name of `c`, so `~` is treated like `\~`.
+pub fn active_char_name(c: char) -> Token {
+    Token::ControlSequence(c.to_string(), Span::any())
+}
+
 /// The tokenizer states as described in chapter 8 of the texbook
-#[derive(Debug, PartialEq, Clone)]
-enum TokenizerState {
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TokenizerState {
+    /// State N, at the beginning of a line
     LineStart,
+    /// State M, in the middle of a line
     LineMiddle,
+    /// State S, skipping blanks
     SkippingBlanks,
 }
 
+/// Callback invoked with the old and the new state on each state change.
+///
+/// The callback must be `Send`, so the tokenizer can still be moved between threads.
+pub type TransitionCallback = Box<dyn FnMut(TokenizerState, TokenizerState) + Send>;
+
+/// Holds the callback registered by `Tokenizer::on_transition`.
+#[derive(Default)]
+struct TransitionHook(Option<TransitionCallback>);
+
+impl std::fmt::Debug for TransitionHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "TransitionHook(Some(..))"),
+            None => write!(f, "TransitionHook(None)"),
+        }
+    }
+}
+
+/// Counts of the tokens generated by a tokenizer so far, see `Tokenizer::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenizerStats {
+    /// Number of control sequences
+    pub control_sequences: usize,
+    /// Number of character tokens, indexed by category
+    characters: [usize; 16],
+    /// Number of comments
+    pub comments: usize,
+    /// Number of skipped runs of input, e.g. spaces or the rest of a line
+    pub skipped: usize,
+}
+
+impl TokenizerStats {
+    /// Number of character tokens of category `cat`.
+    pub fn characters(&self, cat: Category) -> usize {
+        self.characters[cat as usize]
+    }
+
+    fn count(&mut self, token: &Token) {
+        match token {
+            Token::ControlSequence(_, _) => self.control_sequences += 1,
+            Token::Character(_, cat) => self.characters[*cat as usize] += 1,
+            Token::Other(OtherToken::Comment(_), _) => self.comments += 1,
+            Token::Other(OtherToken::Skipped(_), _) => self.skipped += 1,
+            _ => (),
+        }
+    }
+}
+
 /// A token generator for TeX.
 ///
 /// Takes an iterator over input lines and transforms it to a sequence
@@ -125,18 +389,127 @@ enum TokenizerState {
 #[derive(Debug)]
 pub struct Tokenizer<L> {
     category_map: IntIntervalMap<u32, Category>,
+    /// Line given to `tokenize_line`, read before the lines of the iterator
+    pending_line: Option<String>,
+    /// Whether reading from the line iterator is suspended by `tokenize_line`
+    hold_input: bool,
+    /// Category maps saved by `push_catcode_scope`, innermost last
+    catcode_scopes: Vec<IntIntervalMap<u32, Category>>,
     state: TokenizerState,
+    /// Callback for state changes
+    on_transition: TransitionHook,
     lines: L,
     /// Buffer holding the current line
     line: String,
     line_count: usize,
 
+    /// Length of the current line before the endlinechar was appended
+    source_len: usize,
+    /// The endlinechar appended to the current line
+    line_endlinechar: Option<char>,
+    /// Number of input lines joined to the current line
+    joined_lines: usize,
+    /// Whether lines ending with an escape character are joined with the next line
+    join_continuations: bool,
+    /// Whether every character is emitted as a character token
+    raw_mode: bool,
+    /// Whether lines are split at Unicode line and paragraph separators
+    unicode_line_separators: bool,
+    /// Buffer of the previous line, reused for the next line
+    spare_line: String,
+    /// Lines split from the last input line with their offsets, in reverse order
+    separated_lines: Vec<(usize, String)>,
+    /// Whether the origins of lines in the original input are recorded
+    track_original_bytes: bool,
+    /// Byte offset of the next input line in the original input
+    raw_offset: usize,
+    /// Origins of the lines read so far, indexed by line number - 1
+    line_origins: Vec<Option<LineOrigin>>,
     /// Pointer to the current buffer position
     pos: usize,
+    /// Span of the last character read from the input
+    last_char_span: Span,
+    /// Span of the input of the token emitted last, `Span::any()` if it is not from the input
+    emitted_span: Span,
     endlinechar: char,
+    /// Character printed in front of control sequence names
+    escapechar: Option<char>,
+
+    /// Buffer of tokens with the span of their input. Alwas emptied before more TeX tokens
+    /// are generated.
+    token_buffer: Vec<(Token, Span)>,
+    /// Tokens injected by the user, emitted after the token buffer is emptied.
+    injected: Vec<Token>,
+    /// Tokens inserted at the start of each paragraph
+    everypar: Vec<Token>,
+    /// Whether a paragraph was ended by the last \par emitted
+    after_par: bool,
+    /// Whether the end of input was reached
+    finished: bool,
+    /// Counts of the tokens generated so far
+    stats: TokenizerStats,
+    /// Number of tokens emitted so far
+    emitted: usize,
+    /// Whether metadata of the lines and the emitted tokens is recorded
+    track_token_metadata: bool,
+    /// Number of tokens emitted before each line was read, indexed by line number - 1
+    line_starts: Vec<usize>,
+    /// Metadata of the lines read so far
+    line_metadata: Vec<LineMeta>,
+    /// Whether a space generated at the end of a line is in the token buffer
+    end_of_line_space: bool,
+    /// Indices of the emitted tokens which are spaces generated at the end of a line
+    end_of_line_spaces: Vec<usize>,
+    /// Index of the emitted control sequence which led to skipping blanks
+    skipping_after: Option<usize>,
+    /// Indices of the emitted control sequences after which blanks were skipped
+    blanks_skipped_after: Vec<usize>,
+    /// Line of the token emitted last
+    emitted_line: usize,
+    /// Catcode changes scheduled for positions in the current line, latest position first
+    scheduled_catcodes: Vec<(usize, char, Category)>,
+    /// Runaway diagnostic of a character read, emitted after the token of the character
+    runaway: Option<Token>,
+}
 
-    /// Buffer of tokens. Alwas emptied before more TeX tokens are generated.
-    token_buffer: Vec<Token>,
+/// Information about a line read by a tokenizer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineMeta {
+    /// Number of the line, counting from 1
+    pub number: usize,
+    /// The endlinechar appended to the line, if any
+    pub endlinechar: Option<char>,
+    /// Number of trailing spaces removed from the line, including joined lines
+    pub trailing_stripped: usize,
+}
+
+/// Where the text of a preprocessed line comes from in the original input.
+#[derive(Debug, Clone)]
+struct LineOrigin {
+    /// Positions in the line where a joined input line starts, with its original offset
+    segments: Vec<(usize, usize)>,
+    /// Length of the line without the endlinechar
+    source_len: usize,
+    /// Original offset behind the last input line, including stripped spaces
+    end: usize,
+}
+
+impl LineOrigin {
+    /// Original byte offset of byte `pos` of the line.
+    ///
+    /// The endlinechar is located at the end of the original line.
+    fn offset(&self, pos: usize) -> usize {
+        if pos >= self.source_len {
+            return self.end;
+        }
+        let (start, offset) = self
+            .segments
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= pos)
+            .expect("the first segment starts at 0");
+        offset + pos - start
+    }
 }
 
 /// Defines how the tokenizer may be interacted with during tokenization.
@@ -147,22 +520,223 @@ pub trait TokenizerInteraction {
     /// For more information, refer to page 39 of the TeXbook.
     fn catcode(&mut self, chr: char, category: Category);
 
+    /// Make `chr` an active character, i.e. assign it category 13.
+    fn make_active(&mut self, chr: char) {
+        self.catcode(chr, Cat13);
+    }
+
+    /// Make all characters from `from` up to and including `to` active.
+    fn make_active_range(&mut self, from: char, to: char) {
+        for chr in from..=to {
+            self.make_active(chr);
+        }
+    }
+
+    /// Change the category of character `chr` to `cat` in all catcode scopes.
+    ///
+    /// Unlike `catcode`, the assignment survives `pop_catcode_scope`,
+    /// like a \global\catcode assignment in TeX.
+    fn catcode_global(&mut self, chr: char, category: Category);
+
+    /// Save the current category codes, like entering a group in TeX.
+    fn push_catcode_scope(&mut self);
+
+    /// Restore the category codes saved by the matching `push_catcode_scope`.
+    ///
+    /// Returns `false` and leaves the category codes unchanged if no scope was pushed.
+    fn pop_catcode_scope(&mut self) -> bool;
+
+    /// Restore the category codes of plain TeX, which are used by a new tokenizer.
+    fn reset_catcodes(&mut self);
+
     /// Change the endlinechar to `chr`. (See p. 48 of the TeXBook).
     ///
     /// If greater than 255, no character is appended to the line,
     /// which is equivalent to ending the line with a comment in plain TeX.
+    /// This corresponds to setting \endlinechar to a negative value or a value
+    /// greater than 255 in TeX.
     fn set_endlinechar(&mut self, chr: char);
 
+    /// Stop appending an endlinechar to the input lines.
+    ///
+    /// This is equivalent to ending each line with a comment.
+    fn set_endline_comment(&mut self) {
+        self.set_endlinechar(from_u32(256).unwrap());
+    }
+
     /// Get the current value of \endlinechar.
     fn get_endlinechar(&self) -> char;
+
+    /// Get the current category of character `chr`.
+    fn get_catcode(&self, chr: char) -> Category;
+
+    /// Get the current category of the character with code `code`, like \catcode in TeX.
+    ///
+    /// Codes which are no valid characters, e.g. surrogates, have category 12.
+    fn get_catcode_u32(&self, code: u32) -> Category {
+        from_u32(code).map_or(Cat12, |chr| self.get_catcode(chr))
+    }
+
+    /// The current input line number, like \inputlineno in TeX.
+    ///
+    /// As the tokenizer reads ahead of the emitted tokens, this is the line of the token
+    /// emitted last, counting from 1. Injected tokens and tokens of \everypar count for
+    /// the line of the input read when they are emitted. Before any token was emitted,
+    /// this is 0.
+    fn input_line_no(&self) -> usize;
+
+    /// Change the escapechar, which is printed in front of control sequence names.
+    ///
+    /// This does not change which characters start a control sequence, as these are
+    /// the characters of category 0. It only affects the reconstruction of the source
+    /// by `Tokenizer::to_source`, like \escapechar in TeX.
+    fn set_escapechar(&mut self, chr: char) {
+        self.set_escapechar_opt(Some(chr));
+    }
+
+    /// Change the escapechar, or stop printing one if `chr` is `None`.
+    ///
+    /// This corresponds to setting \escapechar to a negative value in TeX.
+    fn set_escapechar_opt(&mut self, chr: Option<char>);
+
+    /// Get the current escapechar, if any.
+    fn get_escapechar(&self) -> Option<char>;
+
+    /// Schedule `tokens` to be emitted before any more input is read.
+    ///
+    /// The tokens are emitted after the already generated tokens, but before
+    /// the tokenizer scans further input. They are not processed by the tokenizer,
+    /// so subsequent catcode changes do not affect them.
+    fn inject_after(&mut self, tokens: Vec<Token>);
+
+    /// Skip the spaces following the current position, like \ignorespaces in TeX.
+    ///
+    /// The tokenizer behaves as after a control word, i.e. it skips blanks
+    /// up to the next non-space character or the end of the line.
+    fn ignore_following_spaces(&mut self);
+
+    /// Set the tokens emitted at the start of each paragraph, like \everypar in TeX.
+    ///
+    /// The tokens are emitted right before the first token after a \par control sequence
+    /// which is neither a space, another \par, nor a non-TeX token.
+    fn set_everypar(&mut self, tokens: Vec<Token>);
 }
 
-impl<L: Iterator<Item = String>> Iterator for Tokenizer<L> {
+impl<L: Iterator> Iterator for Tokenizer<L>
+where
+    L::Item: AsRef<str>,
+{
     type Item = Token;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let token = self.next_emitted()?;
+        self.emitted += 1;
+        self.emitted_line = self.line_count;
+        Some(token)
+    }
+}
+
+impl<'a, L: Iterator<Item = &'a str>> Tokenizer<L> {
+    /// Create a new tokenizer over borrowed `lines`, e.g. from `str::lines`.
+    ///
+    /// This is the same as `Tokenizer::new`. Each line is copied into a buffer of the
+    /// tokenizer, which is reused for the following lines, so no line is allocated.
+    pub fn new_borrowed(lines: L) -> Self {
+        Tokenizer::new(lines)
+    }
+}
+
+impl Tokenizer<std::vec::IntoIter<String>> {
+    /// Create a tokenizer over `input`, which is split into lines at `\r\n`, `\r` or `\n`.
+    ///
+    /// Each of the line endings counts as a single line break, so files with Windows,
+    /// classic Mac and Unix line endings are tokenized alike. A line ending at the end
+    /// of `input` does not start another line.
+    pub fn from_str_crlf(input: &str) -> Self {
+        let mut lines = vec![];
+        let mut rest = input;
+        while !rest.is_empty() {
+            let (line, next) = match rest.find(['\r', '\n']) {
+                Some(i) if rest[i..].starts_with("\r\n") => (&rest[..i], &rest[i + 2..]),
+                Some(i) => (&rest[..i], &rest[i + 1..]),
+                None => (rest, ""),
+            };
+            lines.push(line.to_owned());
+            rest = next;
+        }
+        Tokenizer::new(lines.into_iter())
+    }
+}
+
+/// Tokenize a string, split into lines as by `Tokenizer::from_str_crlf`.
+impl FromStr for Tokenizer<std::vec::IntoIter<String>> {
+    type Err = Infallible;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Ok(Tokenizer::from_str_crlf(input))
+    }
+}
+
+/// Once the end of input is reached, no more tokens are generated,
+/// even if the line iterator is not fused itself.
+impl<L: Iterator> FusedIterator for Tokenizer<L> where L::Item: AsRef<str> {}
+
+impl<L: Iterator> Tokenizer<L>
+where
+    L::Item: AsRef<str>,
+{
+    /// Generate the next token, inserting the tokens of \everypar.
+    fn next_emitted(&mut self) -> Option<Token> {
+        if self.finished {
+            return None;
+        }
+        let token = match self.next_token() {
+            Some(t) => t,
+            None => {
+                // the end of a line given to `tokenize_line` is not the end of input
+                self.finished = !self.hold_input;
+                return None;
+            }
+        };
+        match token {
+            Token::ControlSequence(ref name, _) if name == "par" => self.after_par = true,
+            Token::Character(_, Cat10) | Token::Other(_, _) => (),
+            _ if self.after_par => {
+                self.after_par = false;
+                if !self.everypar.is_empty() {
+                    self.token_buffer.push((token, self.emitted_span.clone()));
+                    let inserted = self.everypar.iter().rev().map(|t| (t.clone(), Span::any()));
+                    self.token_buffer.extend(inserted);
+                    let (token, span) = self.token_buffer.pop()?;
+                    self.emitted_span = span;
+                    return Some(token);
+                }
+            }
+            _ => (),
+        }
+        Some(token)
+    }
+
+    /// Generate the next token by the state machine.
+    fn next_token(&mut self) -> Option<Token> {
         // emtpy token buffer first, if available
-        if let Some(t) = self.token_buffer.pop() {
+        if let Some((t, span)) = self.token_buffer.pop() {
+            self.emitted_span = span;
+            if self.end_of_line_space && matches!(t, Token::Character(' ', _)) {
+                self.end_of_line_space = false;
+                if self.track_token_metadata {
+                    self.end_of_line_spaces.push(self.emitted);
+                }
+            }
+            if let Token::ControlSequence(_, _) = t {
+                if self.state == TokenizerState::SkippingBlanks {
+                    self.skipping_after = Some(self.emitted);
+                }
+            }
+            return Some(t);
+        };
+        if let Some(t) = self.injected.pop() {
+            self.emitted_span = Span::any();
             return Some(t);
         };
 
@@ -179,15 +753,22 @@ impl<L: Iterator<Item = String>> Iterator for Tokenizer<L> {
                     if !self.next_line() {
                         return None;
                     }
-                    self.state = TokenizerState::LineStart;
+                    self.set_state(TokenizerState::LineStart);
                     here = self.here();
                 }
             };
         }
         here.end = self.pos - 1;
+        self.last_char_span = here.clone();
 
         let cat = self.cat(chr);
 
+        if self.raw_mode {
+            self.push(Token::Character(chr, cat));
+            self.push_runaway();
+            return self.next_token();
+        }
+
         // process state as described in chapter 8, p. 46 of the texbook
         match cat {
             Cat0 => match self.pop_char() {
@@ -206,43 +787,50 @@ impl<L: Iterator<Item = String>> Iterator for Tokenizer<L> {
                                     _ => break,
                                 }
                             }
-                            self.state = TokenizerState::SkippingBlanks;
+                            self.set_state(TokenizerState::SkippingBlanks);
                         }
-                        Cat10 => self.state = TokenizerState::SkippingBlanks,
-                        _ => self.state = TokenizerState::LineMiddle,
+                        Cat10 => self.set_state(TokenizerState::SkippingBlanks),
+                        // after a control symbol, spaces are not skipped (state M)
+                        _ => self.set_state(TokenizerState::LineMiddle),
                     };
                     here.end = self.pos - 1;
                     self.push(Token::ControlSequence(content, here));
                 }
             },
             Cat1 | Cat2 | Cat3 | Cat4 | Cat6 | Cat7 | Cat8 | Cat11 | Cat12 | Cat13 => {
-                self.state = TokenizerState::LineMiddle;
+                self.set_state(TokenizerState::LineMiddle);
                 self.push(Token::Character(chr, cat))
             }
             Cat5 => {
+                let mut loc = self.here();
+                match self.state {
+                    TokenizerState::LineStart => {
+                        self.push(Token::ControlSequence("par".into(), here))
+                    }
+                    TokenizerState::LineMiddle => {
+                        self.end_of_line_space = true;
+                        self.push(Token::Character(' ', self.cat(' ')))
+                    }
+                    TokenizerState::SkippingBlanks => self.record_skipped_blanks(),
+                }
                 // throw away rest of line
                 let mut skipped = String::new();
                 while let Some(c) = self.pop_char() {
                     skipped.push(c)
                 }
                 if !skipped.is_empty() {
-                    let mut loc = here.clone();
                     loc.end = self.pos - 1;
                     self.push(Token::Other(OtherToken::Skipped(skipped), loc));
                 }
-                match self.state {
-                    TokenizerState::LineStart => {
-                        self.push(Token::ControlSequence("par".into(), here))
-                    }
-                    TokenizerState::LineMiddle => self.push(Token::Character(' ', self.cat(' '))),
-                    TokenizerState::SkippingBlanks => (),
-                }
+                // the rest of the line is not read by TeX, so it cannot be runaway
+                self.runaway = None;
             }
             Cat9 => {
                 self.push(Token::Other(OtherToken::IgnoredCharacter(chr), here));
             }
             Cat10 => match self.state {
                 TokenizerState::LineStart | TokenizerState::SkippingBlanks => {
+                    self.record_skipped_blanks();
                     let mut whitespace = String::new();
                     whitespace.push(chr);
                     let mut loc = here.clone();
@@ -259,7 +847,7 @@ impl<L: Iterator<Item = String>> Iterator for Tokenizer<L> {
                     self.push(Token::Other(OtherToken::Skipped(whitespace), loc))
                 }
                 TokenizerState::LineMiddle => {
-                    self.state = TokenizerState::SkippingBlanks;
+                    self.set_state(TokenizerState::SkippingBlanks);
                     self.push(Token::Character(' ', self.cat(' ')))
                 }
             },
@@ -271,12 +859,15 @@ impl<L: Iterator<Item = String>> Iterator for Tokenizer<L> {
                 }
                 here.end = self.pos - 1;
                 self.push(Token::Other(OtherToken::Comment(comment), here));
+                // comments are not read by TeX, so they cannot be runaway
+                self.runaway = None;
             }
             Cat15 => {
                 self.push(Token::Other(OtherToken::InvalidCharacter(chr), here));
             }
         };
-        self.next()
+        self.push_runaway();
+        self.next_token()
     }
 }
 
@@ -290,11 +881,85 @@ macro_rules! assign {
     };
 }
 
-impl<L: Iterator<Item = String>> TokenizerInteraction for Tokenizer<L> {
+/// Category of `chr` in plain TeX, as assigned by a new tokenizer.
+///
+/// The category map is only built once, so this is cheaper than creating a tokenizer.
+pub fn default_category(chr: char) -> Category {
+    default_categories().get(chr as u32)
+}
+
+/// Lazily built category assignments of plain TeX
+fn default_categories() -> &'static IntIntervalMap<u32, Category> {
+    static MAP: OnceLock<IntIntervalMap<u32, Category>> = OnceLock::new();
+    MAP.get_or_init(default_category_map)
+}
+
+/// Category assignments of plain TeX
+fn default_category_map() -> IntIntervalMap<u32, Category> {
+    let mut map = IntIntervalMap::new(Category::Cat12);
+
+    assign!(map, '\\', Cat0);
+    assign!(map, '{', Cat1);
+    assign!(map, '}', Cat2);
+    assign!(map, '$', Cat3);
+    assign!(map, '&', Cat4);
+    assign!(map, '\n', Cat5);
+    assign!(map, '\r', Cat5);
+    assign!(map, '#', Cat6);
+    assign!(map, '^', Cat7);
+    assign!(map, '_', Cat8);
+    assign!(map, '\0', Cat9);
+    assign!(map, ' ', Cat10);
+    assign!(map, '\t', Cat10);
+    assign!(map, 'a', 'z', Cat11);
+    assign!(map, 'A', 'Z', Cat11);
+    assign!(map, '0', '9', Cat12);
+    assign!(map, '0', '9', Cat12);
+    assign!(map, ':', '@', Cat12);
+
+    assign!(map, '~', Cat13);
+    assign!(map, '%', Cat14);
+    assign!(map, '\x01', '\x08', Cat15);
+    assign!(map, '\x0b', Cat15);
+    assign!(map, '\x0c', Cat15);
+    assign!(map, '\x0e', '\x1f', Cat15);
+
+    map
+}
+
+impl<L: Iterator> TokenizerInteraction for Tokenizer<L>
+where
+    L::Item: AsRef<str>,
+{
     fn catcode(&mut self, chr: char, cat: Category) {
         self.category_map.assign_single(chr as u32, cat);
     }
 
+    fn catcode_global(&mut self, chr: char, cat: Category) {
+        self.category_map.assign_single(chr as u32, cat);
+        for map in self.catcode_scopes.iter_mut() {
+            map.assign_single(chr as u32, cat);
+        }
+    }
+
+    fn push_catcode_scope(&mut self) {
+        self.catcode_scopes.push(self.category_map.clone());
+    }
+
+    fn pop_catcode_scope(&mut self) -> bool {
+        match self.catcode_scopes.pop() {
+            Some(map) => {
+                self.category_map = map;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn reset_catcodes(&mut self) {
+        self.category_map = default_categories().clone();
+    }
+
     fn set_endlinechar(&mut self, chr: char) {
         self.endlinechar = chr;
     }
@@ -302,53 +967,681 @@ impl<L: Iterator<Item = String>> TokenizerInteraction for Tokenizer<L> {
     fn get_endlinechar(&self) -> char {
         self.endlinechar
     }
+
+    fn set_escapechar_opt(&mut self, chr: Option<char>) {
+        self.escapechar = chr;
+    }
+
+    fn get_escapechar(&self) -> Option<char> {
+        self.escapechar
+    }
+
+    fn get_catcode(&self, chr: char) -> Category {
+        self.cat(chr)
+    }
+
+    fn input_line_no(&self) -> usize {
+        self.emitted_line
+    }
+
+    fn inject_after(&mut self, tokens: Vec<Token>) {
+        // the injected tokens are popped from the end
+        self.injected.splice(0..0, tokens.into_iter().rev());
+    }
+
+    fn ignore_following_spaces(&mut self) {
+        self.set_state(TokenizerState::SkippingBlanks);
+    }
+
+    fn set_everypar(&mut self, tokens: Vec<Token>) {
+        self.everypar = tokens;
+    }
 }
 
-impl<L: Iterator<Item = String>> Tokenizer<L> {
+impl<L: Iterator> Tokenizer<L>
+where
+    L::Item: AsRef<str>,
+{
     /// Create a new tokenizer over `lines` with default character class assignments.
     pub fn new(lines: L) -> Self {
-        let mut map = IntIntervalMap::new(Category::Cat12);
-
-        assign!(map, '\\', Cat0);
-        assign!(map, '{', Cat1);
-        assign!(map, '}', Cat2);
-        assign!(map, '$', Cat3);
-        assign!(map, '&', Cat4);
-        assign!(map, '\n', Cat5);
-        assign!(map, '\r', Cat5);
-        assign!(map, '#', Cat6);
-        assign!(map, '^', Cat7);
-        assign!(map, '_', Cat8);
-        assign!(map, '\0', Cat9);
-        assign!(map, ' ', Cat10);
-        assign!(map, '\t', Cat10);
-        assign!(map, 'a', 'z', Cat11);
-        assign!(map, 'A', 'Z', Cat11);
-        assign!(map, '0', '9', Cat12);
-        assign!(map, '0', '9', Cat12);
-        assign!(map, ':', '@', Cat12);
-
-        assign!(map, '~', Cat13);
-        assign!(map, '%', Cat14);
-        assign!(map, '\x01', '\x08', Cat15);
-        assign!(map, '\x0b', Cat15);
-        assign!(map, '\x0c', Cat15);
-        assign!(map, '\x0e', '\x1f', Cat15);
-
         Tokenizer {
-            category_map: map,
+            category_map: default_categories().clone(),
+            catcode_scopes: vec![],
+            pending_line: None,
+            hold_input: false,
             state: TokenizerState::LineStart,
+            on_transition: TransitionHook::default(),
             lines,
             line: String::new(),
             endlinechar: '\r',
+            escapechar: Some('\\'),
+            source_len: 0,
+            line_endlinechar: None,
+            joined_lines: 0,
+            join_continuations: false,
+            raw_mode: false,
+            unicode_line_separators: false,
+            spare_line: String::new(),
+            separated_lines: vec![],
+            track_original_bytes: false,
+            raw_offset: 0,
+            line_origins: vec![],
             pos: 0,
+            last_char_span: Span::any(),
+            emitted_span: Span::any(),
             token_buffer: vec![],
+            injected: vec![],
+            everypar: vec![],
+            after_par: false,
+            finished: false,
+            stats: TokenizerStats::default(),
+            emitted: 0,
+            track_token_metadata: false,
+            line_starts: vec![],
+            line_metadata: vec![],
+            end_of_line_space: false,
+            end_of_line_spaces: vec![],
+            skipping_after: None,
+            blanks_skipped_after: vec![],
+            scheduled_catcodes: vec![],
+            runaway: None,
+            emitted_line: 0,
             line_count: 0,
         }
     }
+
+    /// Record where each line comes from in the original input, see `original_bytes`.
+    pub fn track_original_bytes(mut self, track: bool) -> Self {
+        self.track_original_bytes = track;
+        self
+    }
+
+    /// Record metadata of the lines read and the tokens emitted, see `line_metadata`,
+    /// `skipped_blanks_after`, `emitted_to_source` and `retokenize_line`.
+    ///
+    /// The metadata grows with the input, so it is only recorded if it is needed.
+    pub fn track_token_metadata(mut self, track: bool) -> Self {
+        self.track_token_metadata = track;
+        self
+    }
+
+    /// Join lines ending with an escape character with the next line.
+    ///
+    /// This is not done by TeX, but by some preprocessors. The escape character
+    /// is removed and the next line is appended without a line break.
+    /// The tokens of joined lines are located in the first of the joined lines.
+    pub fn join_continuations(mut self, join: bool) -> Self {
+        self.join_continuations = join;
+        self
+    }
+
+    /// Emit each input character as a character token with its category.
+    ///
+    /// In raw mode, no control sequences are built and no input is skipped,
+    /// e.g. escape characters, spaces and comment characters are emitted as
+    /// character tokens. Superscript-escaped characters are still replaced.
+    pub fn raw_mode(mut self, raw: bool) -> Self {
+        self.raw_mode = raw;
+        self
+    }
+
+    /// Emit tabs as characters of category 12 instead of spaces, e.g. for code listings.
+    ///
+    /// Only spaces are removed from the end of input lines, so trailing tabs are kept.
+    /// Disabling this gives tabs category 10 again, as in plain TeX.
+    pub fn literal_tabs(mut self, literal: bool) -> Self {
+        self.catcode('\t', if literal { Cat12 } else { Cat10 });
+        self
+    }
+
+    /// Treat the Unicode line separator (U+2028) and paragraph separator (U+2029)
+    /// as line breaks.
+    ///
+    /// Input lines are split at these characters before preprocessing, each part being
+    /// numbered as a line of its own. A paragraph separator additionally inserts an empty
+    /// line, so it generates a \par token.
+    pub fn unicode_line_separators(mut self, split: bool) -> Self {
+        self.unicode_line_separators = split;
+        self
+    }
+
+    /// Stop tokenizing and recover the input which was not consumed yet.
+    ///
+    /// Returns the rest of the current line and the iterator over the remaining lines.
+    /// The current line has already been preprocessed, i.e. trailing spaces are removed
+    /// and the endlinechar is appended.
+    ///
+    /// Tokens which were generated but not yet emitted are lost.
+    pub fn into_remaining(self) -> (String, L) {
+        let rest = self.input().to_owned();
+        (rest, self.lines)
+    }
+
+    /// The endlinechar which was appended to the current line.
+    ///
+    /// As the endlinechar may be changed in the middle of a line, this can differ from
+    /// the current value of \endlinechar. Returns `None` if no character was appended
+    /// or no line was read yet.
+    pub fn current_endlinechar(&self) -> Option<char> {
+        self.line_endlinechar
+    }
+
+    /// Metadata of all lines read so far, in the order they were read.
+    ///
+    /// Lines joined by `join_continuations` share one entry. Returns `None` unless
+    /// `track_token_metadata` is enabled.
+    pub fn line_metadata(&self) -> Option<&[LineMeta]> {
+        if !self.track_token_metadata {
+            return None;
+        }
+        Some(&self.line_metadata)
+    }
+
+    /// The range of character codes sharing the category of `chr`, together with the category.
+    ///
+    /// The range is half-open and contains `chr`'s code, e.g. `97..123` for the letter `a`
+    /// under the default category map.
+    pub fn catcode_range(&self, chr: char) -> (Range<u32>, Category) {
+        let (range, cat) = self.category_map.get_range(chr as u32);
+        (range, *cat)
+    }
+
+    /// The categories of the character codes in `codes`, as ranges of equal category.
+    ///
+    /// The ranges are half-open, in ascending order and cover `codes`, e.g. `0..128`
+    /// lists the categories of all ASCII characters.
+    pub fn catcode_ranges(&self, codes: Range<u32>) -> Vec<(Range<u32>, Category)> {
+        self.category_map
+            .range(codes)
+            .map(|(range, cat)| (range, *cat))
+            .collect()
+    }
+
+    /// Tokenize exactly one line, without reading from the line iterator.
+    ///
+    /// Tokens still pending from earlier input are returned first. The endlinechar is
+    /// appended to `line` as usual, and category codes and the state of the tokenizer
+    /// are preserved for the next call, so a REPL can feed its input line by line.
+    pub fn tokenize_line(&mut self, line: String) -> Vec<Token> {
+        self.pending_line = Some(line);
+        self.hold_input = true;
+        // the line is tokenized even if the line iterator is exhausted
+        let finished = std::mem::replace(&mut self.finished, false);
+        let tokens = self.by_ref().collect();
+        self.hold_input = false;
+        self.finished = finished;
+        tokens
+    }
+
+    /// The byte range of `span` in the original input, before preprocessing.
+    ///
+    /// The original input is assumed to be the input lines, each terminated by a single
+    /// newline character. Spaces stripped from the end of a line are included in the range
+    /// of a span ending with the endlinechar, which itself is located at the newline.
+    ///
+    /// Returns `None` unless `track_original_bytes` is enabled and the line of `span` was read.
+    pub fn original_bytes(&self, span: &Span) -> Option<Range<usize>> {
+        if span.is_any() {
+            return None;
+        }
+        let origin = self.line_origins.get(span.line.checked_sub(1)?)?.as_ref()?;
+        let start = origin.offset(span.start);
+        let end = if span.end >= origin.source_len {
+            origin.end
+        } else {
+            origin.offset(span.end) + 1
+        };
+        Some(start..end)
+    }
+
+    /// Reconstruct TeX source from `tokens`.
+    ///
+    /// Control sequences are printed with the current escapechar. Without an escapechar,
+    /// only their names are printed, so the result cannot be tokenized to the same tokens.
+    /// A space is inserted after a control word if it would merge with the following
+    /// letter otherwise.
+    ///
+    /// Comments and skipped input are reproduced, so the source of a line ending with a
+    /// comment round-trips. As the endlinechar is part of a comment, it is replaced by a
+    /// line break. The endlinechar of each line is known if `track_token_metadata` is
+    /// enabled, otherwise the current one is assumed. \par generated at the end of a line
+    /// is printed as such.
+    /// Runaway diagnostics only repeat input of other tokens and are left out.
+    ///
+    /// `tokens` may be any list of tokens, so all spaces are printed as spaces. For the
+    /// tokens emitted by this tokenizer, `emitted_to_source` replaces the spaces generated
+    /// at the end of a line by line breaks instead.
+    pub fn to_source(&self, tokens: &[Token]) -> String {
+        self.reconstruct(tokens, None)
+    }
+
+    /// Reconstruct TeX source from the tokens emitted by this tokenizer, starting with the
+    /// token emitted as number `first`.
+    ///
+    /// This works like `to_source`, but the spaces which were generated at the end of a
+    /// line are replaced by line breaks, so no spaces are added to the source. `tokens`
+    /// must be a contiguous part of the emitted tokens, e.g. `&emitted[first..]`, or the
+    /// tokens returned by `retokenize_line`. Otherwise, the wrong spaces are replaced.
+    ///
+    /// Returns `None` unless `track_token_metadata` is enabled, as the spaces are not
+    /// known otherwise.
+    pub fn emitted_to_source(&self, first: usize, tokens: &[Token]) -> Option<String> {
+        if !self.track_token_metadata {
+            return None;
+        }
+        Some(self.reconstruct(tokens, Some(first)))
+    }
+
+    /// Reconstruct TeX source, see `to_source`. `first` is the index of the first token
+    /// among the emitted tokens, if `tokens` were emitted by this tokenizer.
+    fn reconstruct(&self, tokens: &[Token], first: Option<usize>) -> String {
+        let mut source = String::new();
+        for (i, token) in tokens.iter().enumerate() {
+            let end_of_line_space = first
+                .is_some_and(|first| self.end_of_line_spaces.binary_search(&(first + i)).is_ok());
+            match token {
+                Token::Character(' ', _) if end_of_line_space => source.push('\n'),
+                Token::ControlSequence(name, _) => {
+                    source.extend(self.escapechar);
+                    source.push_str(name);
+                    let control_word =
+                        !name.is_empty() && name.chars().all(|c| self.cat(c) == Cat11);
+                    let next = tokens[i + 1..]
+                        .iter()
+                        .find(|t| !matches!(t, Token::Other(OtherToken::Runaway(_), _)));
+                    if control_word && matches!(next, Some(Token::Character(_, Cat11))) {
+                        source.push(' ');
+                    }
+                }
+                Token::Character(c, _) => source.push(*c),
+                Token::Parameter(n) => source.push_str(&format!("#{}", n)),
+                Token::Other(OtherToken::Comment(text), span) => {
+                    source.push('%');
+                    let endlinechar = self.endlinechar_of(span.line);
+                    let text = endlinechar
+                        .and_then(|c| text.strip_suffix(c))
+                        .unwrap_or(text);
+                    source.push_str(text);
+                    source.push('\n');
+                }
+                Token::Other(other, _) => source.push_str(&other.to_string()),
+            }
+        }
+        source
+    }
+
+    /// The endlinechar appended to line `line`, as recorded in `line_metadata`.
+    ///
+    /// For lines which were not recorded, the current endlinechar is assumed.
+    fn endlinechar_of(&self, line: usize) -> Option<char> {
+        match self
+            .line_metadata
+            .binary_search_by_key(&line, |meta| meta.number)
+        {
+            Ok(index) => self.line_metadata[index].endlinechar,
+            Err(_) => Some(self.endlinechar).filter(|c| *c as u32 <= 255),
+        }
+    }
+
+    /// Replace the tokens of line `line_number` in `cached` by the tokens of `new_text`.
+    ///
+    /// `cached` must be the tokens emitted by this tokenizer, which is used to find the
+    /// tokens of the line. The new line is tokenized with the current category codes and
+    /// endlinechar, assuming they did not change in the middle of the document. As a line
+    /// always starts in state N, no other state is carried over from the previous line.
+    /// Lines joined by `join_continuations` are not supported. The tokenizer is updated,
+    /// so further lines can be replaced in the returned tokens, and the metadata of the
+    /// line describes the new text.
+    ///
+    /// Returns `None` unless `track_token_metadata` is enabled and the line was read,
+    /// as the tokens of the line are not known otherwise.
+    pub fn retokenize_line(
+        &mut self,
+        line_number: usize,
+        new_text: String,
+        cached: &[Token],
+    ) -> Option<Vec<Token>> {
+        if !self.track_token_metadata {
+            return None;
+        }
+        let index = line_number.checked_sub(1)?;
+        let start = *self.line_starts.get(index)?;
+        let end = self.line_starts.get(index + 1).map_or(cached.len(), |s| *s);
+        if start > end || end > cached.len() {
+            return None;
+        }
+
+        let mut line_tokenizer =
+            Tokenizer::new(std::iter::once(new_text)).track_token_metadata(true);
+        line_tokenizer.category_map = self.category_map.clone();
+        line_tokenizer.endlinechar = self.endlinechar;
+        line_tokenizer.line_count = index;
+        let line_tokens: Vec<Token> = line_tokenizer.by_ref().collect();
+
+        let added = line_tokens.len();
+        for s in self.line_starts.iter_mut().skip(index + 1) {
+            *s = *s + added - (end - start);
+        }
+        let replace = |indices: &mut Vec<usize>, line_indices: &[usize]| {
+            *indices = indices
+                .iter()
+                .filter(|i| **i < start)
+                .cloned()
+                .chain(line_indices.iter().map(|i| i + start))
+                .chain(
+                    indices
+                        .iter()
+                        .filter(|i| **i >= end)
+                        .map(|i| i + added - (end - start)),
+                )
+                .collect();
+        };
+        replace(
+            &mut self.end_of_line_spaces,
+            &line_tokenizer.end_of_line_spaces,
+        );
+        replace(
+            &mut self.blanks_skipped_after,
+            &line_tokenizer.blanks_skipped_after,
+        );
+        if let (Ok(i), Some(meta)) = (
+            self.line_metadata
+                .binary_search_by_key(&line_number, |meta| meta.number),
+            line_tokenizer.line_metadata.pop(),
+        ) {
+            self.line_metadata[i] = meta;
+        }
+        let mut tokens = cached[..start].to_vec();
+        tokens.extend(line_tokens);
+        tokens.extend_from_slice(&cached[end..]);
+        Some(tokens)
+    }
+
+    /// Whether blanks were skipped after the control sequence emitted as token `index`.
+    ///
+    /// After a control word or a control space, spaces are ignored. This tells apart
+    /// e.g. `\foo bar` from `\foo{bar}`. The end of a line counts as a blank, as it
+    /// would produce a space otherwise. Indices count the tokens emitted so far,
+    /// as for `retokenize_line`.
+    ///
+    /// Returns `None` unless `track_token_metadata` is enabled.
+    pub fn skipped_blanks_after(&self, index: usize) -> Option<bool> {
+        if !self.track_token_metadata {
+            return None;
+        }
+        Some(self.blanks_skipped_after.binary_search(&index).is_ok())
+    }
+
+    /// Assign category `cat` to `chr` once byte position `pos` of the current line is reached.
+    ///
+    /// The input before `pos` is read with the old category, the input from `pos` on
+    /// with the new one. Before the first line is read, `pos` refers to the first line.
+    /// Changes for positions which were already passed are applied before the next
+    /// character is read. If the line ends before `pos`, the change is applied when the
+    /// next line is read.
+    pub fn schedule_catcode_at(&mut self, pos: usize, chr: char, cat: Category) {
+        let index = self
+            .scheduled_catcodes
+            .partition_point(|(p, _, _)| *p > pos);
+        self.scheduled_catcodes.insert(index, (pos, chr, cat));
+    }
+
+    /// Call `callback` with the old and the new state whenever the state changes.
+    ///
+    /// This replaces a previously registered callback.
+    pub fn on_transition(&mut self, callback: TransitionCallback) {
+        self.on_transition = TransitionHook(Some(callback));
+    }
+
+    /// Release memory which is not needed for the current state of the tokenizer.
+    pub fn shrink_to_fit(&mut self) {
+        self.category_map.shrink_to_fit();
+        self.token_buffer.shrink_to_fit();
+        self.injected.shrink_to_fit();
+    }
+
+    /// Emit only the tokens for which `keep` returns `true`, e.g. to drop comments.
+    pub fn filtered(self, keep: impl Fn(&Token) -> bool) -> impl Iterator<Item = Token> {
+        self.filter(move |token| keep(token))
+    }
+
+    /// Emit at most `max` tokens, e.g. to bound the work spent on untrusted input.
+    ///
+    /// No further input is read once the limit is reached.
+    pub fn take_limited(self, max: usize) -> impl Iterator<Item = Token> {
+        self.take(max)
+    }
+
+    /// Counts of the tokens generated from the input so far.
+    ///
+    /// Injected tokens and tokens of \everypar are not counted.
+    pub fn stats(&self) -> TokenizerStats {
+        self.stats.clone()
+    }
+
+    /// Check the invariants of the category map, e.g. that its intervals are merged.
+    ///
+    /// In debug builds, this is also asserted after each catcode assignment.
+    pub fn debug_validate(&self) -> Result<(), String> {
+        self.category_map.validate()
+    }
+
+    /// All characters which currently have category 0, in ascending order.
+    pub fn escape_chars(&self) -> Vec<char> {
+        self.category_map
+            .iter()
+            .filter(|(_, cat)| **cat == Cat0)
+            .flat_map(|(range, _)| range.filter_map(from_u32))
+            .collect()
+    }
+
+    /// The category of each character of `text` under the current category codes.
+    ///
+    /// Superscript escapes are resolved, but neither tokens are formed nor is the
+    /// state of the tokenizer changed, e.g. `\` and `%` are classified like
+    /// any other character.
+    pub fn classify(&self, text: &str) -> Vec<(char, Category)> {
+        let mut classes = vec![];
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            let (chr, length) = self.superscript_char_in(rest).unwrap_or((c, c.len_utf8()));
+            classes.push((chr, self.cat(chr)));
+            rest = &rest[length..];
+        }
+        classes
+    }
+
+    /// Consume tokens up to and including the next control sequence called `name`.
+    ///
+    /// Returns the span of the control sequence, or `None` if the end of input
+    /// was reached before.
+    pub fn skip_until_cs(&mut self, name: &str) -> Option<Span> {
+        for token in self {
+            match token {
+                Token::ControlSequence(n, span) if n == name => return Some(span),
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /// Consume a group delimited by characters of category 1 and 2.
+    ///
+    /// Non-TeX tokens before the begin group character are skipped.
+    /// On success, the span from the begin group character to the matching end group
+    /// character is returned. As spans are limited to a single line, `line` is the
+    /// line of the begin group character while `end` is the column of the end group
+    /// character in its line.
+    ///
+    /// If the next token is not a begin group character, its span is returned as
+    /// `TextileError::Syntax`.
+    /// If the end of input is reached before the group is closed, the error is the
+    /// span of the end of input.
+    pub fn skip_group(&mut self) -> Result<Span, TextileError> {
+        let mut span = loop {
+            match self.next() {
+                Some(Token::Other(_, _)) => (),
+                Some(Token::Character(_, Cat1)) => break self.emitted_span.clone(),
+                Some(t) => {
+                    return Err(TextileError::Syntax(
+                        t.span()
+                            .cloned()
+                            .unwrap_or_else(|| self.emitted_span.clone()),
+                    ))
+                }
+                None => return Err(TextileError::Syntax(self.here())),
+            }
+        };
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next() {
+                Some(Token::Character(_, Cat1)) => depth += 1,
+                Some(Token::Character(_, Cat2)) => depth -= 1,
+                Some(_) => (),
+                None => return Err(TextileError::Syntax(self.here())),
+            }
+        }
+        if !self.emitted_span.is_any() {
+            span.end = self.emitted_span.end;
+        }
+        Ok(span)
+    }
+
+    /// Read an undelimited macro argument, i.e. a single token or a balanced group.
+    ///
+    /// Leading spaces are skipped. The braces of a group are stripped, but inner groups
+    /// are kept. Non-TeX tokens are dropped, as they are not part of the argument.
+    ///
+    /// If the argument starts with an end group character, its span is returned as
+    /// `TextileError::Syntax`.
+    /// If the end of input is reached before the argument is complete, the error is the
+    /// span of the end of input.
+    pub fn read_argument(&mut self) -> Result<Vec<Token>, TextileError> {
+        self.read_argument_with(false)
+            .map(|(argument, _)| argument)
+            .map_err(TextileError::Syntax)
+    }
+
+    /// Read an undelimited macro argument like `read_argument`, closing groups left open
+    /// at the end of input.
+    ///
+    /// Closing braces are synthesized for the open groups, so the partial argument stays
+    /// balanced. They are returned together with one zero-width span for each open group
+    /// where its brace was inserted, innermost group first. The last span belongs to the
+    /// group of the argument itself, whose brace is stripped as for a complete argument,
+    /// so there is one span more than synthesized braces. This is meant for showing the
+    /// structure of incomplete input, e.g. while it is being typed.
+    ///
+    /// If the argument starts with an end group character or no argument is left, the
+    /// error is the same as for `read_argument`.
+    pub fn read_argument_recovering(&mut self) -> Result<(Vec<Token>, Vec<Span>), TextileError> {
+        self.read_argument_with(true).map_err(TextileError::Syntax)
+    }
+
+    /// Read the argument of \verb, i.e. verbatim text enclosed by a delimiter character.
+    ///
+    /// This must be called right after the \verb control sequence was emitted. Leading
+    /// spaces are skipped and the next character is the delimiter, which may be any
+    /// character including a letter. The text up to the next occurrence of the delimiter
+    /// is read from the line as it is, without tokenizing it or resolving escapes.
+    /// Returns the delimiter, the text and the span from the opening to the closing
+    /// delimiter.
+    ///
+    /// As with \verb in LaTeX, the text must end in the same line. Otherwise, the error
+    /// is the span of the end of the line and no input is consumed. The text is also
+    /// not available if tokens were generated or injected but not emitted yet, as their
+    /// input was already read. Then the error is the span of the next pending token.
+    pub fn read_verb(&mut self) -> Result<(char, String, Span), TextileError> {
+        if let Some((_, span)) = self.token_buffer.last() {
+            return Err(TextileError::Syntax(span.clone()));
+        }
+        if !self.injected.is_empty() {
+            return Err(TextileError::Syntax(Span::any()));
+        }
+        let line_end = self.source_len.max(self.pos);
+        let rest = &self.line[self.pos..line_end];
+        let skipped = rest.len() - rest.trim_start_matches(' ').len();
+        let end_of_line = TextileError::Syntax(Span::at(self.line_count, line_end));
+        let start = self.pos + skipped;
+        let delimiter = self.line[start..line_end]
+            .chars()
+            .next()
+            .ok_or(end_of_line.clone())?;
+        let text_start = start + delimiter.len_utf8();
+        let length = self.line[text_start..line_end]
+            .find(delimiter)
+            .ok_or(end_of_line)?;
+        let text = self.line[text_start..text_start + length].to_owned();
+        self.pos = text_start + length + delimiter.len_utf8();
+        self.set_state(TokenizerState::LineMiddle);
+        Ok((
+            delimiter,
+            text,
+            Span::new(self.line_count, start, self.pos - 1),
+        ))
+    }
+
+    fn read_argument_with(&mut self, recover: bool) -> Result<(Vec<Token>, Vec<Span>), Span> {
+        loop {
+            match self.next() {
+                Some(Token::Other(_, _)) | Some(Token::Character(_, Cat10)) => (),
+                Some(Token::Character(_, Cat1)) => break,
+                Some(Token::Character(_, Cat2)) => return Err(self.emitted_span.clone()),
+                Some(t) => return Ok((vec![t], vec![])),
+                None => return Err(self.here()),
+            }
+        }
+        let mut argument = vec![];
+        let mut depth = 1;
+        loop {
+            let token = match self.next() {
+                Some(t) => t,
+                None if recover => {
+                    let synthesized = vec![Span::at(self.line_count, self.pos); depth];
+                    // the brace of the outermost group is stripped, but it keeps its span
+                    argument.extend((1..depth).map(|_| Token::Character('}', Cat2)));
+                    return Ok((argument, synthesized));
+                }
+                None => return Err(self.here()),
+            };
+            match token {
+                Token::Other(_, _) => continue,
+                Token::Character(_, Cat1) => depth += 1,
+                Token::Character(_, Cat2) if depth == 1 => return Ok((argument, vec![])),
+                Token::Character(_, Cat2) => depth -= 1,
+                _ => (),
+            }
+            argument.push(token);
+        }
+    }
 }
 
-impl<L: Iterator<Item = String>> Tokenizer<L> {
+impl<L: Iterator> Tokenizer<L>
+where
+    L::Item: AsRef<str>,
+{
+    /// Change the state of the tokenizer, reporting changes to the transition callback.
+    fn set_state(&mut self, state: TokenizerState) {
+        if state != TokenizerState::SkippingBlanks {
+            self.skipping_after = None;
+        }
+        if self.state != state {
+            if let Some(callback) = self.on_transition.0.as_mut() {
+                callback(self.state, state);
+            }
+            self.state = state;
+        }
+    }
+
+    /// Note that blanks were skipped after the control sequence which led to state S.
+    fn record_skipped_blanks(&mut self) {
+        if let Some(index) = self.skipping_after.take() {
+            if self.track_token_metadata {
+                self.blanks_skipped_after.push(index);
+            }
+        }
+    }
+
     /// Span of the next input character
     fn here(&self) -> Span {
         Span::new(self.line_count, self.pos, self.pos)
@@ -361,7 +1654,20 @@ impl<L: Iterator<Item = String>> Tokenizer<L> {
 
     /// Push a syntax token into the buffer.
     fn push(&mut self, token: Token) {
-        self.token_buffer.insert(0, token);
+        self.stats.count(&token);
+        // character tokens are pushed right after their input was read
+        let span = token
+            .span()
+            .cloned()
+            .unwrap_or_else(|| self.last_char_span.clone());
+        self.token_buffer.insert(0, (token, span));
+    }
+
+    /// Push the runaway diagnostic of the characters read for the last token, if any.
+    fn push_runaway(&mut self) {
+        if let Some(runaway) = self.runaway.take() {
+            self.push(runaway);
+        }
     }
 
     /// Advance to the next line of input.
@@ -371,33 +1677,192 @@ impl<L: Iterator<Item = String>> Tokenizer<L> {
     /// if the end of input was reached.
     #[must_use = "the end of input must be handled"]
     fn next_line(&mut self) -> bool {
-        self.state = TokenizerState::LineStart;
-        let mut line = match self.lines.next() {
-            Some(l) => l,
-            None => return false,
+        self.set_state(TokenizerState::LineStart);
+        // changes scheduled behind the end of the previous line are due now
+        if self.line_count > 0 {
+            for (_, chr, cat) in std::mem::take(&mut self.scheduled_catcodes)
+                .into_iter()
+                .rev()
+            {
+                self.catcode(chr, cat);
+            }
+        }
+        // the line is built in the spare buffer, so no allocation is needed in general
+        let mut line = std::mem::take(&mut self.spare_line);
+        line.clear();
+        let offset = match self.read_line(&mut line) {
+            Some(offset) => offset,
+            None => {
+                self.spare_line = line;
+                return false;
+            }
         };
+        let mut segments = vec![];
+        if self.track_original_bytes {
+            segments.push((0, offset));
+        }
+        let mut end = offset + line.len();
+        strip_carriage_return(&mut line, 0);
+        let mut trailing_stripped = line.len();
         line.truncate(line.trim_end_matches(' ').len());
+        trailing_stripped -= line.len();
+        self.line_count += 1 + self.joined_lines;
+        self.joined_lines = 0;
+        if self.join_continuations {
+            while self.ends_with_escape(&line) {
+                let escape = line.pop();
+                let start = line.len();
+                let offset = match self.read_line(&mut line) {
+                    Some(offset) => offset,
+                    None => {
+                        line.extend(escape);
+                        break;
+                    }
+                };
+                if self.track_original_bytes {
+                    segments.push((start, offset));
+                }
+                end = offset + line.len() - start;
+                strip_carriage_return(&mut line, start);
+                let joined_len = line[start..].trim_end_matches(' ').len();
+                trailing_stripped += line.len() - start - joined_len;
+                line.truncate(start + joined_len);
+                self.joined_lines += 1;
+            }
+        }
+        self.source_len = line.len();
+        if self.track_token_metadata {
+            self.line_starts.resize(self.line_count, self.emitted);
+        }
+        if self.track_original_bytes {
+            let index = self.line_count - 1;
+            if self.line_origins.len() <= index {
+                self.line_origins.resize(index + 1, None);
+            }
+            self.line_origins[index] = Some(LineOrigin {
+                segments,
+                source_len: self.source_len,
+                end,
+            });
+        }
+        self.line_endlinechar = None;
         if self.endlinechar as u32 <= 255 {
             line.push(self.endlinechar);
+            self.line_endlinechar = Some(self.endlinechar);
         }
-        self.line = line;
+        if self.track_token_metadata {
+            self.line_metadata.push(LineMeta {
+                number: self.line_count,
+                endlinechar: self.line_endlinechar,
+                trailing_stripped,
+            });
+        }
+        self.spare_line = std::mem::replace(&mut self.line, line);
         self.pos = 0;
-        self.line_count += 1;
         true
     }
 
+    /// Append the next line to `buffer`, preferring the lines queued by `split_separators`.
+    ///
+    /// Returns the byte offset of the line in the original input.
+    fn read_line(&mut self, buffer: &mut String) -> Option<usize> {
+        if let Some((offset, l)) = self.separated_lines.pop() {
+            buffer.push_str(&l);
+            return Some(offset);
+        }
+        let start = buffer.len();
+        let offset = self.fetch_line(buffer)?;
+        if self.unicode_line_separators {
+            return Some(self.split_separators(offset, buffer, start));
+        }
+        Some(offset)
+    }
+
+    /// Append the next raw input line to `buffer`, preferring a line given to `tokenize_line`.
+    ///
+    /// Returns the byte offset of the line in the original input.
+    fn fetch_line(&mut self, buffer: &mut String) -> Option<usize> {
+        let length = buffer.len();
+        match self.pending_line.take() {
+            Some(l) => buffer.push_str(&l),
+            None if self.hold_input => return None,
+            None => buffer.push_str(self.lines.next()?.as_ref()),
+        }
+        let offset = self.raw_offset;
+        self.raw_offset += buffer.len() - length + 1;
+        Some(offset)
+    }
+
+    /// Split `line` from byte `from` on at Unicode line and paragraph separators.
+    ///
+    /// The split part of `line` is replaced by the first line, the others are queued.
+    /// Returns the offset of the first line.
+    fn split_separators(&mut self, offset: usize, line: &mut String, from: usize) -> usize {
+        let mut lines = vec![];
+        let mut start = offset;
+        for (i, part) in line[from..].split('\u{2029}').enumerate() {
+            if i > 0 {
+                // an empty line ends the paragraph
+                lines.push((start - '\u{2029}'.len_utf8(), String::new()));
+            }
+            // both separators are encoded with three bytes
+            for l in part.split('\u{2028}') {
+                lines.push((start, l.to_owned()));
+                start += l.len() + '\u{2028}'.len_utf8();
+            }
+        }
+        lines.reverse();
+        let (first_offset, first) = lines.pop().unwrap();
+        self.separated_lines.extend(lines);
+        line.truncate(from);
+        line.push_str(&first);
+        first_offset
+    }
+
+    /// Whether `line` ends with an escape character which does not start
+    /// a control symbol, e.g. `\\`.
+    fn ends_with_escape(&self, line: &str) -> bool {
+        let escapes = line
+            .chars()
+            .rev()
+            .take_while(|c| self.cat(*c) == Cat0)
+            .count();
+        escapes % 2 == 1
+    }
+
+    /// Apply the scheduled catcode changes whose position was reached.
+    fn apply_scheduled_catcodes(&mut self) {
+        while let Some((pos, chr, cat)) = self.scheduled_catcodes.last().cloned() {
+            if pos > self.pos {
+                break;
+            }
+            self.scheduled_catcodes.pop();
+            self.catcode(chr, cat);
+        }
+    }
+
     /// pop the next character from the current line.
     /// the character might have been esacped,
     /// which consumes more input than one character.
     fn pop_char(&mut self) -> Option<char> {
+        self.apply_scheduled_catcodes();
         match self.parse_superscript_char() {
             Some((c, l)) => {
+                // a hex digit at the end of the line might be an incomplete hex escape
+                let escape: String = self.input().chars().take(3).collect();
+                if l == escape.len()
+                    && self.pos + l >= self.source_len
+                    && escape.chars().nth(2).is_some_and(is_lower_hexdigit)
+                {
+                    let span = Span::new(self.line_count, self.pos, self.pos + l - 1);
+                    self.runaway = Some(Token::Other(OtherToken::Runaway(escape), span));
+                }
                 self.pos += l;
                 Some(c)
             }
             None => match self.input().chars().next() {
                 Some(c) => {
-                    self.pos += 1;
+                    self.pos += c.len_utf8();
                     Some(c)
                 }
                 None => None,
@@ -407,7 +1872,8 @@ impl<L: Iterator<Item = String>> Tokenizer<L> {
 
     /// get the next character of the input
     /// with escaped characters normalized
-    fn look_ahead(&self) -> Option<char> {
+    fn look_ahead(&mut self) -> Option<char> {
+        self.apply_scheduled_catcodes();
         match self.parse_superscript_char() {
             Some((c, _)) => Some(c),
             None => self.input().chars().next(),
@@ -421,28 +1887,53 @@ impl<L: Iterator<Item = String>> Tokenizer<L> {
 
     /// Parse a superscript-escaped character (e.g. ^^A or ^^0f).
     ///
+    /// As in TeX, the replacement character may start another escape together with
+    /// the following input, e.g. ^^5e^41 is an escaped A. As each escape consumes
+    /// further input, this terminates.
+    ///
     /// Returns the replacement character and length of consumed input, if successful
     fn parse_superscript_char(&self) -> Option<(char, usize)> {
-        let mut chars = self.input().chars();
-        let c_start = match chars.next().filter(|c| self.cat(*c) == Cat7) {
-            Some(c) => c,
-            None => return None,
-        };
-        if chars.next() == Some(c_start) {
+        self.superscript_char_in(self.input())
+    }
+
+    /// Parse a superscript-escaped character at the start of `input`.
+    fn superscript_char_in(&self, input: &str) -> Option<(char, usize)> {
+        let first = input.chars().next()?;
+        let (mut chr, used) = self.decode_superscript(first, &input[first.len_utf8()..])?;
+        let mut length = first.len_utf8() + used;
+        while let Some((c, used)) = self.decode_superscript(chr, &input[length..]) {
+            chr = c;
+            length += used;
+        }
+        Some((chr, length))
+    }
+
+    /// Decode the escape made of the superscript character `first` and the start of `rest`.
+    ///
+    /// Returns the replacement character and the length of consumed input of `rest`.
+    fn decode_superscript(&self, first: char, rest: &str) -> Option<(char, usize)> {
+        if self.cat(first) != Cat7 {
+            return None;
+        }
+        let mut chars = rest.chars();
+        if chars.next() == Some(first) {
             let next_two = [chars.next(), chars.next()];
 
-            let are_hexdigits = next_two.iter().all(|o| {
-                o.map(|c| (c.is_ascii_hexdigit() && c.is_lowercase()) || c.is_numeric())
-                    .unwrap_or(false)
-            });
+            let are_hexdigits = next_two
+                .iter()
+                .all(|o| o.map(is_lower_hexdigit).unwrap_or(false));
+
+            // length of the second superscript character
+            let prefix = first.len_utf8();
 
             if are_hexdigits {
+                let digits: String = next_two.iter().flatten().collect();
                 let chr = from_u32(
-                    u32::from_str_radix(&self.input()[2..4], 16)
+                    u32::from_str_radix(&digits, 16)
                         .expect("parse error with superscript-escaped hex character"),
                 )
                 .expect("unicode error in superscript-escaped character!");
-                return Some((chr, 4));
+                return Some((chr, prefix + digits.len()));
             }
 
             if let Some(c) = next_two[0] {
@@ -455,9 +1946,24 @@ impl<L: Iterator<Item = String>> Tokenizer<L> {
                 } else {
                     from_u32(c as u32 - 64).unwrap()
                 };
-                return Some((chr, 3));
+                return Some((chr, prefix + c.len_utf8()));
             }
         }
         None
     }
 }
+
+/// Remove the `\r` of a CRLF line ending from a line starting at `start` of `line`.
+///
+/// Lines split at `\n` only keep the `\r`. As it has category 5 by default, it would
+/// end the line before the endlinechar, which is then skipped as the rest of the line.
+fn strip_carriage_return(line: &mut String, start: usize) {
+    if line.len() > start && line.ends_with('\r') {
+        line.pop();
+    }
+}
+
+/// Digits allowed in hex-escaped characters (see p. 45 of the texbook)
+fn is_lower_hexdigit(c: char) -> bool {
+    matches!(c, '0'..='9' | 'a'..='f')
+}
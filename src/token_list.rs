@@ -0,0 +1,506 @@
+/// Utilities operating on lists of tokens.
+use crate::error::TextileError;
+use crate::token::*;
+
+/// Transform each token of `tokens` with `f`.
+pub fn map_tokens(tokens: Vec<Token>, f: impl FnMut(Token) -> Token) -> Vec<Token> {
+    tokens.into_iter().map(f).collect()
+}
+
+/// Move tokens of a fragment to their location in a larger document.
+///
+/// Lines are shifted by `offset_line`. As only the first line of the fragment
+/// starts in the middle of a document line, columns are shifted by `offset_col`
+/// for the first line only. Dummy spans (see `Span::any()`) are left untouched.
+pub fn remap_spans(tokens: &mut [Token], offset_line: usize, offset_col: usize) {
+    for span in tokens.iter_mut().filter_map(Token::span_mut) {
+        if span.is_any() {
+            continue;
+        }
+        if span.line == 1 {
+            span.start += offset_col;
+            span.end += offset_col;
+        }
+        span.line += offset_line;
+    }
+}
+
+/// Render `tokens` as a human-readable listing, one token per line.
+///
+/// Each line starts with the location of the token as `line:start-end`,
+/// or `-` if it is not known, followed by the kind of token and its content.
+pub fn dump_tokens(tokens: &[Token]) -> String {
+    let mut result = String::new();
+    for token in tokens {
+        let location = match token.span() {
+            Some(span) if !span.is_any() => format!("{}:{}-{}", span.line, span.start, span.end),
+            _ => "-".to_owned(),
+        };
+        let description = match token {
+            Token::ControlSequence(name, _) => format!("ControlSequence {:?}", name),
+            Token::Character(c, cat) => format!("Character {:?} {:?}", c, cat),
+            Token::Parameter(n) => format!("Parameter {}", n),
+            Token::Other(other, _) => format!("Other {:?}", other),
+        };
+        result.push_str(&format!("{:<12}{}\n", location, description));
+    }
+    result
+}
+
+/// Describe the first difference between two token lists.
+///
+/// Returns `None` if the lists are equal.
+pub fn token_diff(expected: &[Token], actual: &[Token]) -> Option<String> {
+    let length = expected.len().max(actual.len());
+    (0..length).find_map(|i| match (expected.get(i), actual.get(i)) {
+        (Some(e), Some(a)) if e == a => None,
+        (e, a) => Some(format!(
+            "tokens differ at index {}: expected {}, got {}",
+            i,
+            e.map_or("nothing".to_owned(), |t| format!("{:?}", t)),
+            a.map_or("nothing".to_owned(), |t| format!("{:?}", t)),
+        )),
+    })
+}
+
+/// Split `tokens` into the cells of an alignment.
+///
+/// Cells are separated by alignment characters (category 4), except for
+/// those inside of groups. The separators are not part of the cells.
+pub fn split_alignment(tokens: &[Token]) -> Vec<Vec<Token>> {
+    let mut cells = vec![vec![]];
+    let mut depth: usize = 0;
+    for token in tokens {
+        match token {
+            Token::Character(_, Category::Cat1) => depth += 1,
+            Token::Character(_, Category::Cat2) => depth = depth.saturating_sub(1),
+            Token::Character(_, Category::Cat4) if depth == 0 => {
+                cells.push(vec![]);
+                continue;
+            }
+            _ => (),
+        }
+        cells.last_mut().unwrap().push(token.clone());
+    }
+    cells
+}
+
+/// Compare two token lists by content and category only, ignoring all spans.
+pub fn tokens_eq_ignoring_spans(a: &[Token], b: &[Token]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|pair| match pair {
+            (Token::ControlSequence(x, _), Token::ControlSequence(y, _)) => x == y,
+            (Token::Other(x, _), Token::Other(y, _)) => x == y,
+            (x, y) => x == y,
+        })
+}
+
+/// Apply the replacements of the text at the given spans to `source`.
+///
+/// The spans refer to `source` split into lines at `\n`. A span reaching behind the end of
+/// its line, e.g. including the endlinechar, ends with the line; the line break is kept.
+/// The replacements may be given in any order, but must not overlap. Otherwise, or if the
+/// line of a span does not exist, the offending span is returned as `TextileError::Syntax`.
+/// Dummy spans (see `Span::any()`) cannot be located and are rejected, too. At zero-width
+/// spans (see `Span::at()`), the text is inserted without replacing any input.
+pub fn splice(source: &str, replacements: &[(Span, String)]) -> Result<String, TextileError> {
+    let mut line_starts = vec![0];
+    line_starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+    let line_end = |line: usize| line_starts.get(line).map_or(source.len(), |next| next - 1);
+
+    let mut ranges = vec![];
+    for (span, text) in replacements {
+        if span.is_any() || span.line == 0 || span.line > line_starts.len() {
+            return Err(TextileError::Syntax(span.clone()));
+        }
+        let start = line_starts[span.line - 1];
+        let end = line_end(span.line);
+        // a zero-width span is an insertion point
+        let length = if span.is_zero_width() { 0 } else { 1 };
+        let range = (start + span.start).min(end)..(start + span.end + length).min(end);
+        ranges.push((range, span, text));
+    }
+    ranges.sort_by_key(|(range, _, _)| (range.start, range.end));
+
+    let mut result = String::new();
+    let mut copied = 0;
+    for (range, span, text) in ranges {
+        if range.start < copied {
+            return Err(TextileError::Syntax(span.clone()));
+        }
+        result.push_str(&source[copied..range.start]);
+        result.push_str(text);
+        copied = range.end;
+    }
+    result.push_str(&source[copied..]);
+    Ok(result)
+}
+
+/// Find the math formulas outside of groups in `tokens`.
+///
+/// Returns pairs of the indices of the opening and closing math shift characters.
+/// Display formulas are delimited by two math shift characters; for them, the indices
+/// of the first and the last math shift character are returned.
+/// An unterminated formula at the end of `tokens` is ignored.
+pub fn find_math_spans(tokens: &[Token]) -> Vec<(usize, usize)> {
+    let is_math_shift =
+        |i: usize| matches!(tokens.get(i), Some(Token::Character(_, cat)) if cat.is_math_shift());
+    let mut spans = vec![];
+    let mut depth: usize = 0;
+    // index of the opening math shift and whether it is a display formula
+    let mut open: Option<(usize, bool)> = None;
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            Token::Character(_, Category::Cat1) => depth += 1,
+            Token::Character(_, Category::Cat2) => depth = depth.saturating_sub(1),
+            Token::Character(_, cat) if cat.is_math_shift() && depth == 0 => {
+                let display = is_math_shift(i + 1);
+                match open {
+                    None => {
+                        open = Some((i, display));
+                        if display {
+                            i += 1;
+                        }
+                    }
+                    Some((start, true)) if display => {
+                        spans.push((start, i + 1));
+                        open = None;
+                        i += 1;
+                    }
+                    Some((start, _)) => {
+                        spans.push((start, i));
+                        open = None;
+                    }
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    spans
+}
+
+/// Find parameter characters in `tokens` which do not start a parameter token.
+///
+/// A parameter character is stray unless it is followed by a digit from 1 to 9
+/// or another parameter character. As character tokens have no span, the indices
+/// of the stray characters are returned.
+pub fn find_stray_parameters(tokens: &[Token]) -> Vec<usize> {
+    let mut stray = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        if let Token::Character(_, cat) = &tokens[i] {
+            if cat.is_parameter() {
+                match tokens.get(i + 1) {
+                    Some(Token::Character(c, Category::Cat12)) if ('1'..='9').contains(c) => i += 1,
+                    Some(Token::Character(_, cat)) if cat.is_parameter() => i += 1,
+                    _ => stray.push(i),
+                }
+            }
+        }
+        i += 1;
+    }
+    stray
+}
+
+/// Pair each control sequence in `tokens` with the contents of the group following it.
+///
+/// Non-TeX tokens between the control sequence and the group are skipped. The braces of
+/// the group are stripped, but inner groups are kept. A control sequence without a group
+/// comes with an empty list, while a group without a control sequence comes with `None`.
+/// Other tokens outside of groups are dropped. A group which is not closed extends to
+/// the end of `tokens`.
+pub fn cs_with_group(
+    tokens: impl Iterator<Item = Token>,
+) -> impl Iterator<Item = (Option<String>, Vec<Token>)> {
+    let mut tokens = tokens.peekable();
+    std::iter::from_fn(move || loop {
+        let name = match tokens.next()? {
+            Token::ControlSequence(name, _) => {
+                while let Some(Token::Other(_, _)) = tokens.peek() {
+                    tokens.next();
+                }
+                match tokens.peek() {
+                    Some(Token::Character(_, Category::Cat1)) => {
+                        tokens.next();
+                    }
+                    _ => return Some((Some(name), vec![])),
+                }
+                Some(name)
+            }
+            Token::Character(_, Category::Cat1) => None,
+            _ => continue,
+        };
+        let mut group = vec![];
+        let mut depth = 1;
+        for token in tokens.by_ref() {
+            match token {
+                Token::Character(_, Category::Cat1) => depth += 1,
+                Token::Character(_, Category::Cat2) if depth == 1 => break,
+                Token::Character(_, Category::Cat2) => depth -= 1,
+                _ => (),
+            }
+            group.push(token);
+        }
+        return Some((name, group));
+    })
+}
+
+#[cfg(test)]
+mod token_list_test {
+    use crate::token::{Category::*, Token::*};
+    use crate::token_list::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        Tokenizer::new(input.lines().map(|s| s.to_owned())).collect()
+    }
+
+    #[test]
+    fn map_characters() {
+        let upper = map_tokens(tokens("\\a b"), |t| match t {
+            Character(c, cat) => Character(c.to_ascii_uppercase(), cat),
+            t => t,
+        });
+        assert_eq!(
+            upper,
+            vec![
+                ControlSequence("a".into(), Span::new(1, 0, 1)),
+                Other(OtherToken::Skipped(" ".into()), Span::new(1, 2, 2)),
+                Character('B', Cat11),
+                Character(' ', Cat10),
+            ]
+        );
+    }
+
+    #[test]
+    fn shift_spans() {
+        let mut fragment = tokens("\\a b\n\\c");
+        fragment.push(ControlSequence("d".into(), Span::any()));
+        remap_spans(&mut fragment, 4, 10);
+        let spans: Vec<(usize, usize, usize)> = fragment
+            .iter()
+            .filter_map(Token::span)
+            .filter(|s| !s.is_any())
+            .map(|s| (s.line, s.start, s.end))
+            .collect();
+        assert_eq!(spans, vec![(5, 10, 11), (5, 12, 12), (6, 0, 1)]);
+        assert!(fragment.last().and_then(Token::span).unwrap().is_any());
+    }
+
+    #[test]
+    fn dump() {
+        let mut list = tokens("\\ab  %x");
+        list.push(Parameter(1));
+        assert_eq!(
+            dump_tokens(&list),
+            "1:0-2       ControlSequence \"ab\"\n\
+             1:3-4       Other Skipped(\"  \")\n\
+             1:5-7       Other Comment(\"x\\r\")\n\
+             -           Parameter 1\n"
+        );
+    }
+
+    #[test]
+    fn diff() {
+        let expected = tokens("ab c");
+        assert_eq!(token_diff(&expected, &expected), None);
+
+        let mut actual = expected.clone();
+        actual[1] = Character('b', Cat12);
+        assert_eq!(
+            token_diff(&expected, &actual),
+            Some(
+                "tokens differ at index 1: expected Character('b', Cat11), \
+                 got Character('b', Cat12)"
+                    .to_owned()
+            )
+        );
+
+        actual = expected.clone();
+        actual.pop();
+        assert_eq!(
+            token_diff(&expected, &actual),
+            Some(
+                "tokens differ at index 4: expected Character(' ', Cat10), got nothing".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn alignment_cells() {
+        let mut tokenizer = Tokenizer::new("a&{b & c}&  d".lines().map(|s| s.to_owned()));
+        tokenizer.set_endline_comment();
+        let tokens: Vec<Token> = tokenizer.collect();
+        assert_eq!(
+            split_alignment(&tokens),
+            vec![
+                vec![Character('a', Cat11)],
+                vec![
+                    Character('{', Cat1),
+                    Character('b', Cat11),
+                    Character(' ', Cat10),
+                    Character('&', Cat4),
+                    Character(' ', Cat10),
+                    Character('c', Cat11),
+                    Character('}', Cat2),
+                ],
+                vec![
+                    Character(' ', Cat10),
+                    Other(OtherToken::Skipped(" ".into()), Span::new(1, 11, 11)),
+                    Character('d', Cat11),
+                ],
+            ]
+        );
+        assert_eq!(split_alignment(&[]), vec![Vec::<Token>::new()]);
+    }
+
+    #[test]
+    fn math_spans() {
+        assert!(Cat3.is_math_shift() && !Cat4.is_math_shift());
+        assert!(Cat4.is_alignment() && !Cat6.is_alignment());
+        assert!(Cat6.is_parameter() && !Cat3.is_parameter());
+
+        assert_eq!(find_math_spans(&tokens("a $x$ b")), vec![(2, 4)]);
+        assert_eq!(find_math_spans(&tokens("$x")), vec![]);
+        assert_eq!(
+            find_math_spans(&tokens("$$x$$ {$y$} $z$")),
+            vec![(0, 4), (12, 14)]
+        );
+    }
+
+    #[test]
+    fn stray_parameters() {
+        assert_eq!(find_stray_parameters(&tokens("a # b")), vec![2]);
+        assert_eq!(find_stray_parameters(&tokens("#1")), vec![]);
+        assert_eq!(find_stray_parameters(&tokens("##1#")), vec![3]);
+        assert_eq!(find_stray_parameters(&tokens("#0#")), vec![0, 2]);
+    }
+
+    #[test]
+    fn equal_ignoring_spans() {
+        let a = tokens("\\a b%c");
+        let b = tokens("\\a  b%c");
+        assert_ne!(a, b);
+        let mut shifted = tokens("\\a b%c");
+        remap_spans(&mut shifted, 3, 7);
+        assert!(tokens_eq_ignoring_spans(&a, &shifted));
+        assert!(!tokens_eq_ignoring_spans(&a, &b));
+        let mut recategorized = a.clone();
+        recategorized[2] = Character('b', Cat12);
+        assert!(!tokens_eq_ignoring_spans(&a, &recategorized));
+        assert!(!tokens_eq_ignoring_spans(&a, &a[1..]));
+    }
+
+    #[test]
+    fn splice_source() {
+        let source = "a \\foo b\n\\bar%x\nc";
+        let tokens = tokens(source);
+        let span = |name: &str| {
+            tokens
+                .iter()
+                .find_map(|t| match t {
+                    ControlSequence(n, span) if n == name => Some(span.clone()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let comment = tokens
+            .iter()
+            .find_map(|t| match t {
+                Other(OtherToken::Comment(_), span) => Some(span.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            splice(source, &[(span("foo"), "\\baz".into())]),
+            Ok("a \\baz b\n\\bar%x\nc".to_owned())
+        );
+        assert_eq!(
+            splice(
+                source,
+                &[(comment, "".into()), (span("bar"), "\\relax".into())]
+            ),
+            Ok("a \\foo b\n\\relax\nc".to_owned())
+        );
+        assert_eq!(
+            splice(
+                source,
+                &[(span("foo"), "".into()), (Span::new(1, 3, 3), "".into())]
+            ),
+            Err(TextileError::Syntax(Span::new(1, 3, 3)))
+        );
+        assert_eq!(
+            splice(source, &[(Span::new(4, 0, 0), "".into())]),
+            Err(TextileError::Syntax(Span::new(4, 0, 0)))
+        );
+
+        // zero-width spans insert text, even next to replaced input
+        assert_eq!(
+            splice(source, &[(Span::at(1, 2), "\\relax".into())]),
+            Ok("a \\relax\\foo b\n\\bar%x\nc".to_owned())
+        );
+        assert_eq!(
+            splice(
+                source,
+                &[(span("foo"), "x".into()), (Span::at(1, 2), "y".into())]
+            ),
+            Ok("a yx b\n\\bar%x\nc".to_owned())
+        );
+        assert_eq!(
+            splice(source, &[(Span::at(3, 1), "d".into())]),
+            Ok("a \\foo b\n\\bar%x\ncd".to_owned())
+        );
+        assert_eq!(
+            splice(
+                source,
+                &[(span("foo"), "".into()), (Span::at(1, 3), "".into())]
+            ),
+            Err(TextileError::Syntax(Span::at(1, 3)))
+        );
+    }
+
+    #[test]
+    fn control_sequences_with_groups() {
+        let pairs: Vec<(Option<String>, Vec<Token>)> =
+            cs_with_group(tokens("\\section{Intro}\\label{x}").into_iter()).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (
+                    Some("section".into()),
+                    vec![
+                        Character('I', Cat11),
+                        Character('n', Cat11),
+                        Character('t', Cat11),
+                        Character('r', Cat11),
+                        Character('o', Cat11),
+                    ]
+                ),
+                (Some("label".into()), vec![Character('x', Cat11)]),
+            ]
+        );
+
+        let pairs: Vec<(Option<String>, Vec<Token>)> =
+            cs_with_group(tokens("\\par a{b{c}}\\emph %\n {d").into_iter()).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (Some("par".into()), vec![]),
+                (
+                    None,
+                    vec![
+                        Character('b', Cat11),
+                        Character('{', Cat1),
+                        Character('c', Cat11),
+                        Character('}', Cat2),
+                    ]
+                ),
+                (
+                    Some("emph".into()),
+                    vec![Character('d', Cat11), Character(' ', Cat10)]
+                ),
+            ]
+        );
+    }
+}